@@ -2,9 +2,7 @@ use std::boxed::Box;
 use std::io::{ErrorKind, Result};
 use std::net::Ipv4Addr;
 use std;
-use std::ops::Deref;
-use std::path::Path;
-use std::fmt::Display;
+use time;
 
 use allocator;
 use pool;
@@ -12,12 +10,19 @@ use config;
 use frame::ethernet::EthernetAddr;
 use lease;
 use packet;
+use store;
 
 pub struct AllocationUnit {
     selector: config::Selector,
     allocator: allocator::Allocator,
+    /// Options of the pool merged with the ones of the interface and global ones
     options: Box<[packet::DhcpOption]>,
-    lease_time: u32
+    classes: Box<[config::Class]>,
+    mask: Ipv4Addr,
+    always_send: Box<[u8]>,
+    probe: config::Probe,
+    reservations: Box<[config::Reservation]>,
+    quarantine_time: u32
 }
 
 
@@ -27,12 +32,12 @@ impl AllocationUnit {
     fn default_options(opts: &mut Vec<packet::DhcpOption>,
                        alloc: &allocator::Allocator) {
         if !opts.iter().any(|opt| opt.get_type() == 51) {
-            info!("Defaulting lease time");
+            debug!("Defaulting lease time");
             opts.push(packet::DhcpOption::LeaseTime(86_400));
         }
 
         if !opts.iter().any(|opt| opt.get_type() == 1) {
-            warn!("Defaulting SubnetMask");
+            debug!("Defaulting SubnetMask");
 
             let (min, max) = alloc.get_bounds();
             let min_u32: u32 = min.into();
@@ -49,90 +54,293 @@ impl AllocationUnit {
 
     fn new(pool: pool::GPool<Ipv4Addr>,
            sel: config::Selector,
-           mut opts: Vec<packet::DhcpOption>,
+           opts: Vec<packet::DhcpOption>,
            lease: Option<String>,
            alloc: Option<String>,
            dealloc: Option<String>)
            -> Self {
         let allocator = allocator::Allocator::new(pool, alloc, dealloc, lease);
-        Self::default_options(&mut opts, &allocator);
-
-        let options = opts.into_boxed_slice();
+        let mut defaulted = opts.clone();
+        Self::default_options(&mut defaulted, &allocator);
+        if !opts.iter().any(|opt| opt.get_type() == 1) {
+            warn!("Defaulting SubnetMask for {}", allocator.get_name());
+        }
 
         AllocationUnit {
-            lease_time: Self::get_lease_time(options.iter()),
+            mask: Self::get_subnet_mask(&defaulted),
+            classes: Box::new([]),
+            quarantine_time: 3_600,
+            always_send: Box::new([]),
+            probe: config::Probe::Off,
+            reservations: Box::new([]),
             selector: sel,
-            options: options,
+            options: opts.into_boxed_slice(),
             allocator: allocator,
             }
     }
 
-    pub fn from_conf<D: AsRef<Path> + Display>(conf: config::Pool, iface: &str, dir: D) -> Self {
+    /// Create the unit for a pool on `iface`, without any state.
+    /// `inherited` are the options of the interface and global scope, `classes` the global classes.
+    pub fn configure(conf: config::Pool,
+                     iface: &str,
+                     inherited: &[packet::DhcpOption],
+                     classes: &[config::Class]) -> Self {
         let pool = conf.range.get_pool(iface).unwrap();
         info!("Creating allocator for {} with pool {}", iface, pool.get_name());
-        let mut ret = Self::new(pool, conf.selector, conf.options, conf.lease, conf.allocate, conf.deallocate);
-
-        let _ = ret.allocator.read_from(dir.as_ref()).map_err(|e| {
-                match e.kind() {
-                    ErrorKind::NotFound => {
-                        info!("Couldn't find file or directory while loading allocator: {} on {}", ret.get_name(), iface);
-                    },
-                    _ => {
-                            error!("Couldn't read allocator {} on interface {}: {}", ret.get_name(), iface, e);
-                            println!("Couldn't read allocator {} on interface {}: {}", ret.get_name(), iface, e);
-                            std::process::exit(1);
-                        },
+        let mut opts = inherited.to_vec();
+        packet::merge_options(&mut opts, &conf.options);
+        let mut ret = Self::new(pool, conf.selector, opts, conf.lease, conf.allocate, conf.deallocate);
+        if let Some(name) = conf.name {
+            ret.allocator.set_name(name);
+        }
+        ret.classes = classes.to_vec().into_boxed_slice();
+        if let Some(time) = conf.quarantine {
+            ret.quarantine_time = time;
+        }
+        ret.always_send = conf.always_send.into_boxed_slice();
+        ret.probe = conf.probe;
+        ret.reservations = conf.reservations.into_iter().filter(|res| {
+                let ok = ret.allocator.in_range(res.address);
+                if !ok {
+                    error!("Ignoring reservation of {} for {:?}, it's not in the range of {}", res.address, res.host, ret.get_name());
                 }
-            });
+                ok
+            }).collect::<Vec<_>>().into_boxed_slice();
 
         ret
     }
 
-    // We can savely unwrap() here because we enforce the exiistance over default_options called by
-    // new
-    pub fn get_mask(&self) -> &Ipv4Addr {
-        self.options.iter().find(|x| x.get_type() == 1).map(|x| match *x {
-                packet::DhcpOption::SubnetMask(ref mask) => mask,
+    /// Read the state from `store` on startup, we exit if that fails
+    pub fn load_or_exit(&mut self, iface: &str, store: &mut store::LeaseStore) {
+        let _ = self.load(iface, store).map_err(|e| {
+                error!("Couldn't read allocator {} on interface {}: {}", self.get_name(), iface, e);
+                println!("Couldn't read allocator {} on interface {}: {}", self.get_name(), iface, e);
+                std::process::exit(1);
+            });
+        self.apply_reservations();
+    }
+
+    /// Read the state from `store`, it's fine if there's none yet.
+    /// If the last save is damaged we fall back to the one before.
+    /// State written by a newer server is an error, the backup would miss what it changed.
+    pub fn load(&mut self, iface: &str, store: &mut store::LeaseStore) -> Result<()> {
+        match self.allocator.read_from(store) {
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {
+                info!("Couldn't find file or directory while loading allocator: {} on {}", self.get_name(), iface);
+                Ok(())
+            },
+            Err(e) if store::format::is_newer(&e) => Err(e),
+            Err(e) => {
+                error!("The saved state of {} on {} is damaged: {}", self.get_name(), iface, e);
+                self.allocator.read_backup_from(store).map(|()| {
+                    warn!("Using the backup of {} on {}, changes since the save before the last one are lost", self.get_name(), iface);
+                })
+            },
+            Ok(()) => Ok(()),
+        }
+    }
+
+    /// Take over the state for our addresses from a unit that was replaced on reload
+    pub fn adopt(&mut self, old: &mut AllocationUnit) {
+        self.allocator.adopt(&mut old.allocator);
+    }
+
+    /// Whether the unit holds any allocations, leases or quarantined addresses
+    pub fn is_empty(&self) -> bool {
+        self.allocator.is_empty()
+    }
+
+    /// Keep the reserved addresses out of the pool, call this after the state was loaded
+    pub fn apply_reservations(&mut self) {
+        let reserved: Vec<Ipv4Addr> = self.reservations.iter().map(|res| res.address).collect();
+        let reservations = &self.reservations;
+        self.allocator.reserve(&reserved, |client, addr| Self::find_reservation(reservations, client).map(|res| res.address == addr).unwrap_or(false));
+    }
+
+    // We can savely unwrap() here because we enforce the exiistance over default_options
+    fn get_subnet_mask(opts: &[packet::DhcpOption]) -> Ipv4Addr {
+        opts.iter().find(|x| x.get_type() == 1).map(|x| match *x {
+                packet::DhcpOption::SubnetMask(mask) => mask,
                 _ => panic!("Found non SubnetMask SubnetMask"),
             }).unwrap()
     }
 
+    pub fn get_mask(&self) -> &Ipv4Addr {
+        &self.mask
+    }
+
+    /// Check whether `addr` is on the subnet this unit hands out addresses for
+    pub fn serves(&self, addr: Ipv4Addr) -> bool {
+        let mask: u32 = (*self.get_mask()).into();
+        let net: u32 = self.allocator.get_bounds().0.into();
+        let cmp: u32 = addr.into();
+
+        cmp & mask == net & mask
+    }
+
     fn get_lease_time<'a, I: IntoIterator<Item=&'a packet::DhcpOption>>(it: I) -> u32 {
         it.into_iter().find(|x| x.get_type() == 51).map(|x| match *x {
                 packet::DhcpOption::LeaseTime(time) => time,
-                _ => panic!("Found non SubnetMask SubnetMask"),
+                _ => panic!("Found non LeaseTime LeaseTime"),
             }).unwrap()
     }
 
-    pub fn save_to(&self, dir: &std::path::Path) -> Result<()> {
-        self.allocator.save_to(dir)
+    pub fn save_to(&mut self, store: &mut store::LeaseStore) -> Result<()> {
+        self.allocator.save_to(store)
     }
 
     pub fn get_name(&self) -> String { self.allocator.get_name() }
 
-    pub fn is_suitable(&self, client: &lease::Client<::frame::ethernet::EthernetAddr>) -> bool { self.selector.is_suitable(client) }
+    pub fn has_own_name(&self) -> bool { self.allocator.has_own_name() }
+
+    pub fn get_range_name(&self) -> String { self.allocator.get_range_name() }
+
+    pub fn overlaps_range_name(&self, name: &str) -> bool { self.allocator.overlaps_range_name(name) }
+
+    pub fn in_range(&self, addr: Ipv4Addr) -> bool { self.allocator.in_range(addr) }
+
+    /// Take over a lease from another server, see `Allocator::import`
+    pub fn import(&mut self,
+                  client: &lease::Client<EthernetAddr>,
+                  addr: Ipv4Addr,
+                  start: time::Timespec,
+                  duration: Option<u32>) -> std::result::Result<(), String> {
+        if self.reservations.iter().any(|res| res.address == addr) && self.get_reserved(client).map(|res| res.address) != Some(addr) {
+            return Err(format!("{} is reserved for another host", addr));
+        }
+        self.allocator.import(client, addr, start, duration)
+    }
+
+    pub fn is_suitable(&self, client: &lease::Client<EthernetAddr>, request: &packet::DhcpPacket<EthernetAddr>) -> bool { self.selector.is_suitable(client, request, &self.classes) }
+
+    /// The reservation of `client`, one for its MAC or client identifier wins over one for its hostname
+    fn find_reservation<'a>(reservations: &'a [config::Reservation], client: &lease::Client<EthernetAddr>) -> Option<&'a config::Reservation> {
+        reservations.iter().find(|res| !res.host.is_hostname() && res.host.matches(client))
+            .or_else(|| reservations.iter().find(|res| res.host.matches(client)))
+    }
+
+    fn get_reserved(&self, client: &lease::Client<EthernetAddr>) -> Option<&config::Reservation> {
+        Self::find_reservation(&self.reservations, client)
+    }
+
+    /// All options for a client, the ones of its classes and host override the ones of the pool.
+    /// Defaults are filled in after merging.
+    fn merged_options(&self, client: &lease::Client<EthernetAddr>, request: &packet::DhcpPacket<EthernetAddr>) -> Vec<packet::DhcpOption> {
+        let mut opts = self.options.to_vec();
+        for class in self.classes.iter().filter(|class| class.selector.is_suitable(client, request, &self.classes)) {
+            debug!("{} is in class {}", client.hw_addr, class.name);
+            packet::merge_options(&mut opts, &class.options);
+            if let Some(time) = class.lease_time {
+                packet::merge_options(&mut opts, &[packet::DhcpOption::LeaseTime(time)]);
+            }
+        }
+        if let Some(res) = self.get_reserved(client) {
+            packet::merge_options(&mut opts, &res.options);
+        }
+
+        Self::default_options(&mut opts, &self.allocator);
+        opts
+    }
 
-    pub fn get_options(&self) -> &[packet::DhcpOption] { self.options.deref() }
+    /// The options for `client`, filtered by the parameter request list of `request`
+    pub fn get_options_for(&self, client: &lease::Client<EthernetAddr>, request: &packet::DhcpPacket<EthernetAddr>) -> Vec<packet::DhcpOption> {
+        let opts = self.merged_options(client, request);
+        packet::filter_options(&opts, request.get_requested_options(), &self.always_send)
+    }
 
     pub fn get_allocation(&mut self, client: &lease::Client<EthernetAddr>, addr: Option<Ipv4Addr>) -> Option<&lease::Allocation<EthernetAddr, Ipv4Addr>> {
-        self.allocator.get_allocation(client, addr)
+        match self.get_reserved(client).map(|res| res.address) {
+            Some(reserved) => self.allocator.get_reserved(client, reserved),
+            None => self.allocator.get_allocation(client, addr),
+        }
     }
 
-    pub fn get_renewed_lease(&mut self, client: &lease::Client<EthernetAddr>, addr: Option<Ipv4Addr>) -> Option<&lease::Lease<EthernetAddr, Ipv4Addr>> {
-        self.allocator.get_renewed_lease(client, addr, self.lease_time)
+    pub fn get_allocated(&self, client: &lease::Client<EthernetAddr>) -> Option<Ipv4Addr> {
+        self.get_reserved(client).map(|res| res.address).or_else(|| self.allocator.get_allocated(client))
+    }
+
+    pub fn has_allocation(&self, client: &lease::Client<EthernetAddr>, addr: Ipv4Addr) -> bool {
+        match self.get_reserved(client) {
+            Some(res) => res.address == addr,
+            None => self.allocator.has_allocation(client, addr),
+        }
+    }
+
+    pub fn get_renewed_lease(&mut self,
+                             client: &lease::Client<EthernetAddr>,
+                             request: &packet::DhcpPacket<EthernetAddr>,
+                             addr: Option<Ipv4Addr>)
+                             -> Option<&lease::Lease<EthernetAddr, Ipv4Addr>> {
+        let lease_time = Self::get_lease_time(self.merged_options(client, request).iter());
+        match self.get_reserved(client).map(|res| res.address) {
+            Some(reserved) => {
+                let _ = self.allocator.get_reserved(client, reserved);
+                self.allocator.get_renewed_lease(client, Some(reserved), lease_time)
+            },
+            None => self.allocator.get_renewed_lease(client, addr, lease_time),
+        }
     }
 
     pub fn free_lease(&mut self, client: &lease::Client<EthernetAddr>, addr: Ipv4Addr) {
         self.allocator.free_lease(client, addr);
     }
+
+    pub fn drop_offer(&mut self, client: &lease::Client<EthernetAddr>) {
+        self.allocator.drop_offer(client);
+    }
+
+    pub fn decline(&mut self, client: &lease::Client<EthernetAddr>, addr: Ipv4Addr) {
+        self.allocator.decline(client, addr, self.quarantine_time);
+    }
+
+    /// How to check `addr` is unused before offering it to `client`.
+    /// An address the client holds a lease on isn't probed, the client itself would answer.
+    /// Neither are reserved ones, they can't be given to anyone else either way.
+    pub fn get_probe(&self, client: &lease::Client<EthernetAddr>, addr: Ipv4Addr) -> config::Probe {
+        if self.allocator.is_leased(client, addr) || self.get_reserved(client).is_some() {
+            config::Probe::Off
+        } else {
+            self.probe
+        }
+    }
+
+    /// Someone answered a probe for `addr`
+    pub fn conflict(&mut self, addr: Ipv4Addr) {
+        self.allocator.conflict(addr, self.quarantine_time);
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::AllocationUnit;
-    use config::Selector;
+    use config::{Class, Host, Reservation, Selector};
+    use frame::ethernet::{EthernetAddr, MacPattern};
+    use lease;
+    use packet::{DhcpOption, DhcpPacket, PacketType};
     use pool::GPool;
+    use std;
+    use std::io::Write;
     use std::net::Ipv4Addr;
+    use std::str::FromStr;
+    use store::{format, LeaseStore};
+    use store::json::JsonStore;
+    use time;
+
+    fn discover(client: &lease::Client<EthernetAddr>, options: Vec<DhcpOption>) -> DhcpPacket<EthernetAddr> {
+        DhcpPacket {
+            packet_type: PacketType::Discover,
+            xid: 0,
+            seconds: 0,
+            client_addr: None,
+            your_addr: None,
+            server_addr: None,
+            gateway_addr: None,
+            client_hwaddr: client.hw_addr,
+            options: options,
+            flags: Vec::new(),
+            max_size: None,
+        }
+    }
 
     #[test]
     fn defaults_mask() {
@@ -148,4 +356,129 @@ mod test {
         let au3 = AllocationUnit::new(pool3, Selector::All, vec![], None, None, None);
         assert!(au3.get_mask() == &Ipv4Addr::new(255, 255, 128, 0));
     }
+
+    #[test]
+    fn serves_subnet() {
+        let pool = GPool::new(Ipv4Addr::new(10, 0, 1, 100), Ipv4Addr::new(10, 0, 1, 200)).unwrap();
+        let au = AllocationUnit::new(pool, Selector::All, vec![DhcpOption::SubnetMask(Ipv4Addr::new(255, 255, 255, 0))], None, None, None);
+
+        assert!(au.serves(Ipv4Addr::new(10, 0, 1, 1)));
+        assert!(au.serves(Ipv4Addr::new(10, 0, 1, 255)));
+        assert!(!au.serves(Ipv4Addr::new(10, 0, 2, 1)));
+    }
+
+    #[test]
+    fn uses_reservation() {
+        let pool = GPool::new(Ipv4Addr::new(10, 0, 1, 100), Ipv4Addr::new(10, 0, 1, 200)).unwrap();
+        let mut au = AllocationUnit::new(pool, Selector::All, vec![DhcpOption::DomainName("example.com".into())], None, None, None);
+        let client = lease::Client{client_identifier: None, hostname: Some("printer".into()), hw_addr: EthernetAddr([0, 0, 0, 0, 0, 1])};
+        let reserved = Ipv4Addr::new(10, 0, 1, 150);
+        au.reservations = vec![Reservation {
+                host: Host::Hostname("printer".into()),
+                address: reserved,
+                options: vec![DhcpOption::DomainName("printers.example.com".into())],
+            }].into_boxed_slice();
+
+        let request = discover(&client, vec![DhcpOption::ParameterRequestList(vec![15].into_boxed_slice())]);
+
+        assert!(au.get_allocation(&client, None).map(|a| a.assigned == reserved).unwrap_or(false));
+        assert!(au.get_options_for(&client, &request) == vec![DhcpOption::LeaseTime(86_400), DhcpOption::DomainName("printers.example.com".into())]);
+    }
+
+    #[test]
+    fn prefers_mac_reservation_to_hostname() {
+        let pool = GPool::new(Ipv4Addr::new(10, 0, 1, 100), Ipv4Addr::new(10, 0, 1, 200)).unwrap();
+        let mut au = AllocationUnit::new(pool, Selector::All, Vec::new(), None, None, None);
+        let client = lease::Client{client_identifier: None, hostname: Some("printer".into()), hw_addr: EthernetAddr([0, 0, 0, 0, 0, 1])};
+        let by_name = Ipv4Addr::new(10, 0, 1, 150);
+        let by_mac = Ipv4Addr::new(10, 0, 1, 151);
+        au.reservations = vec![
+                Reservation { host: Host::Hostname("printer".into()), address: by_name, options: Vec::new() },
+                Reservation { host: Host::Mac(client.hw_addr), address: by_mac, options: Vec::new() },
+            ].into_boxed_slice();
+        au.apply_reservations();
+
+        assert!(au.get_allocation(&client, None).map(|a| a.assigned == by_mac).unwrap_or(false));
+        assert!(au.import(&client, by_name, time::get_time(), None).is_err());
+    }
+
+    #[test]
+    fn merges_class_options() {
+        let pool = GPool::new(Ipv4Addr::new(10, 0, 1, 100), Ipv4Addr::new(10, 0, 1, 200)).unwrap();
+        let mut au = AllocationUnit::new(pool, Selector::All, vec![DhcpOption::LeaseTime(3600)], None, None, None);
+        let client = lease::Client{client_identifier: None, hostname: None, hw_addr: EthernetAddr([0, 0, 0, 0, 0, 1])};
+        au.classes = vec![Class {
+                name: "short".into(),
+                selector: Selector::Macs(vec![client.hw_addr.into()].into_boxed_slice()),
+                options: vec![DhcpOption::LeaseTime(600)],
+                lease_time: None,
+            }].into_boxed_slice();
+        let request = discover(&client, Vec::new());
+
+        assert!(au.get_renewed_lease(&client, &request, None).map(|l| l.lease_duration == 600).unwrap_or(false));
+    }
+
+    #[test]
+    fn matches_vendor_classes() {
+        let client = lease::Client{client_identifier: None, hostname: None, hw_addr: EthernetAddr([0, 0, 0, 0, 0, 1])};
+        let selector = Selector::VendorClasses(vec![String::from("MSFT"), String::from("PXEClient")].into_boxed_slice());
+        let request = |class: &[u8]| discover(&client, vec![DhcpOption::VendorClassIdentifier(class.to_vec().into_boxed_slice())]);
+
+        assert!(selector.is_suitable(&client, &request(b"MSFT 5.0"), &[]));
+        assert!(selector.is_suitable(&client, &request(b"PXEClient:Arch:00007"), &[]));
+        assert!(!selector.is_suitable(&client, &request(b"android-dhcp-9"), &[]));
+        assert!(!selector.is_suitable(&client, &discover(&client, vec![DhcpOption::ClientIdentifier(b"MSFT"[..].to_vec().into_boxed_slice())]), &[]));
+    }
+
+    #[test]
+    fn matches_class_expressions() {
+        let pool = GPool::new(Ipv4Addr::new(10, 0, 1, 100), Ipv4Addr::new(10, 0, 1, 200)).unwrap();
+        let mut au = AllocationUnit::new(pool, Selector::All, vec![DhcpOption::LeaseTime(3600)], None, None, None);
+        au.classes = vec![
+            Class {
+                name: "pxe".into(),
+                selector: Selector::OptionPrefix(60, "PXEClient".into()),
+                options: Vec::new(),
+                lease_time: None,
+            },
+            Class {
+                name: "pxe-not-ours".into(),
+                selector: Selector::And(vec![
+                        Selector::Class("pxe".into()),
+                        Selector::Not(vec![Selector::Macs(vec![MacPattern::from_str("00:50:56:*").unwrap()].into_boxed_slice())].into_boxed_slice()),
+                    ].into_boxed_slice()),
+                options: Vec::new(),
+                lease_time: Some(300),
+            },
+        ].into_boxed_slice();
+
+        let ours = lease::Client{client_identifier: None, hostname: None, hw_addr: EthernetAddr([0, 0x50, 0x56, 0, 0, 1])};
+        let other = lease::Client{client_identifier: None, hostname: None, hw_addr: EthernetAddr([0, 0x1b, 0x21, 0, 0, 1])};
+        let pxe = vec![DhcpOption::VendorClassIdentifier(b"PXEClient:Arch:00000"[..].to_vec().into_boxed_slice())];
+
+        let lease_time = |au: &mut AllocationUnit, client: &lease::Client<EthernetAddr>, opts: Vec<DhcpOption>|
+            au.get_renewed_lease(client, &discover(client, opts), None).map(|l| l.lease_duration);
+        assert!(lease_time(&mut au, &ours, pxe.clone()) == Some(3600));
+        assert!(lease_time(&mut au, &other, pxe) == Some(300));
+        assert!(lease_time(&mut au, &other, Vec::new()) == Some(3600));
+    }
+
+    #[test]
+    fn refuses_newer_state() {
+        let dir = std::env::temp_dir().join(format!("dhcpd-newer-{}", std::process::id()));
+        let pool = GPool::new(Ipv4Addr::new(10, 0, 1, 100), Ipv4Addr::new(10, 0, 1, 200)).unwrap();
+        let mut au = AllocationUnit::new(pool, Selector::All, vec![], None, None, None);
+        let mut store = JsonStore::new(dir.clone());
+
+        let my_dir = dir.join(au.get_name());
+        std::fs::create_dir_all(&my_dir).unwrap();
+        std::fs::File::create(my_dir.join("state.json")).unwrap().write_all(b"{\"version\":1000}").unwrap();
+        std::fs::File::create(my_dir.join("state.json.bak")).unwrap()
+            .write_all(b"{\"version\":1,\"allocations\":[],\"leases\":[],\"quarantined\":[]}").unwrap();
+
+        assert!(store.read(&au.get_name()).map_err(|e| format::is_newer(&e)).err() == Some(true));
+        assert!(au.load("eth0", &mut store).map_err(|e| format::is_newer(&e)).err() == Some(true));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
 }
@@ -2,15 +2,12 @@ extern crate time;
 use std::iter::Iterator;
 use std;
 use std::io::{Error, ErrorKind, Result};
-
-use std::io::Write;
-use std::io::Read;
-
-extern crate serde;
-extern crate serde_json;
+use std::str::FromStr;
 
 use lease;
 use pool;
+use store;
+use store::journal;
 
 use frame::ethernet::EthernetAddr;
 use std::net::Ipv4Addr;
@@ -20,7 +17,14 @@ use std::net::Ipv4Addr;
 pub struct Allocator {
     allocations: Vec<lease::Allocation<EthernetAddr, Ipv4Addr>>,
     leases: Vec<lease::Lease<EthernetAddr, Ipv4Addr>>,
+    quarantined: Vec<lease::Quarantine<EthernetAddr, Ipv4Addr>>,
     address_pool: pool::GPool<Ipv4Addr>,
+    /// The name the state is stored under, the range if there's none
+    name: Option<String>,
+    /// Entries in the journal since the last snapshot, `None` if the next save has to write one
+    journaled: Option<usize>,
+    /// Addresses whose allocation, lease or quarantine changed since the last save
+    changed: Vec<Ipv4Addr>,
 
     deallocate_hook: Option<String>,
     allocate_hook: Option<String>,
@@ -122,6 +126,92 @@ impl Allocator {
                 }
             }
             self.leases.remove(index);
+            self.touch(addr);
+        }
+    }
+
+    /// Remember to journal the state of `addr` on the next save
+    fn touch(&mut self, addr: Ipv4Addr) {
+        self.changed.push(addr);
+    }
+
+    /// A client told us the address is already in use (DHCPDECLINE).
+    /// Drop its lease and allocation and keep the address out of the pool for `duration` seconds.
+    pub fn decline(&mut self, client: &lease::Client<EthernetAddr>, addr: Ipv4Addr, duration: u32) {
+        let index = match self.allocations.iter().position(|alloc| alloc.assigned == addr) {
+                Some(x) => x,
+                None => {
+                    warn!("{} declined {}, which isn't allocated", client.hw_addr, addr);
+                    return;
+                }
+            };
+
+        if !client.overlapping(&self.allocations[index].client) {
+            warn!("{} tried to decline {}, which is allocated to another client", client.hw_addr, addr);
+            return;
+        }
+
+        self.leases.retain(|l| l.assigned != addr);
+        let alloc = self.allocations.swap_remove(index);
+        self.del_alloc(alloc);
+
+        self.quarantine(client, addr, duration);
+    }
+
+    /// `client` took the offer of another server, the address we offered goes back to the pool.
+    /// Reserved addresses and ones the client still has a lease on are kept.
+    pub fn drop_offer(&mut self, client: &lease::Client<EthernetAddr>) {
+        let index = match self.allocations.iter().position(|alloc| alloc.client.hw_addr == client.hw_addr
+                                                            || (client.client_identifier.is_some() && alloc.client.client_identifier == client.client_identifier)) {
+                Some(x) => x,
+                None => return,
+            };
+        let addr = self.allocations[index].assigned;
+        if self.allocations[index].forever || self.leases.iter().any(|l| l.assigned == addr && l.is_active()) {
+            return;
+        }
+
+        info!("Returning {} offered to {} to the pool", addr, client.hw_addr);
+        let alloc = self.allocations[index].clone();
+        self.drop_allocation(index);
+        self.del_alloc(alloc);
+    }
+
+    /// Something else on the network uses `addr`.
+    /// Drop the allocation for it and keep the address out of the pool for `duration` seconds.
+    pub fn conflict(&mut self, addr: Ipv4Addr, duration: u32) {
+        if let Some(index) = self.allocations.iter().position(|alloc| alloc.assigned == addr) {
+            self.leases.retain(|l| l.assigned != addr);
+            let alloc = self.allocations.swap_remove(index);
+            let client = alloc.client.clone();
+            self.del_alloc(alloc);
+
+            self.quarantine(&client, addr, duration);
+        }
+    }
+
+    fn quarantine(&mut self, client: &lease::Client<EthernetAddr>, addr: Ipv4Addr, duration: u32) {
+        info!("Quarantining {} for {} seconds", addr, duration);
+        self.touch(addr);
+        self.address_pool.set_used(&addr);
+        self.quarantined.retain(|q| q.assigned != addr);
+        self.quarantined.push(lease::Quarantine {
+            assigned: addr,
+            client: client.clone(),
+            start: lease::SerializeableTime(time::get_time()),
+            duration: duration,
+            });
+    }
+
+    /// Return addresses with an expired quarantine to the pool
+    fn end_quarantines(&mut self) {
+        let (active, expired): (Vec<_>, Vec<_>) = self.quarantined.drain(..).partition(|q| q.is_active());
+        self.quarantined = active;
+
+        for q in expired {
+            info!("Quarantine for {} ended", q.assigned);
+            self.address_pool.set_unused(&q.assigned);
+            self.touch(q.assigned);
         }
     }
 
@@ -135,7 +225,7 @@ impl Allocator {
     }
 
     pub fn new(p: pool::GPool<Ipv4Addr>, allocate: Option<String>, deallocate: Option<String>, lease: Option<String>) -> Allocator {
-        Allocator { address_pool: p, leases: Vec::new(), allocations: Vec::new(), allocate_hook: allocate, lease_hook: lease, deallocate_hook: deallocate}
+        Allocator { address_pool: p, name: None, leases: Vec::new(), quarantined: Vec::new(), allocations: Vec::new(), journaled: None, changed: Vec::new(), allocate_hook: allocate, lease_hook: lease, deallocate_hook: deallocate}
     }
 
     fn find_allocation(&self, client: &lease::Client<EthernetAddr>) -> Option<usize> {
@@ -146,6 +236,85 @@ impl Allocator {
             .or_else(|| self.allocations.iter().position(|alloc| client.hostname.is_some() && alloc.client.hostname == client.hostname))
     }
 
+    /// The address currently allocated to `client`, without creating an allocation
+    pub fn get_allocated(&self, client: &lease::Client<EthernetAddr>) -> Option<Ipv4Addr> {
+        self.find_allocation(client).map(|i| self.allocations[i].assigned)
+    }
+
+    /// Check whether `addr` is allocated to `client`, without creating an allocation
+    pub fn has_allocation(&self, client: &lease::Client<EthernetAddr>, addr: Ipv4Addr) -> bool {
+        self.allocations.iter().any(|alloc| alloc.assigned == addr && client.overlapping(&alloc.client))
+    }
+
+    /// Keep the reserved addresses out of the pool.
+    /// Allocations of reserved addresses for other clients than the owner are dropped.
+    pub fn reserve<F>(&mut self, reserved: &[Ipv4Addr], is_owner: F)
+        where F: Fn(&lease::Client<EthernetAddr>, Ipv4Addr) -> bool {
+        for addr in reserved {
+            self.address_pool.set_used(addr);
+        }
+
+        let (keep, foreign): (Vec<_>, Vec<_>) = self.allocations.drain(..)
+            .partition(|alloc| !reserved.contains(&alloc.assigned) || is_owner(&alloc.client, alloc.assigned));
+        self.allocations = keep;
+
+        // The reservation was removed from the config, the address can be reclaimed like any other
+        let mut released = Vec::new();
+        for alloc in self.allocations.iter_mut().filter(|alloc| alloc.forever && !reserved.contains(&alloc.assigned)) {
+            info!("Address {} of {:?} isn't reserved anymore", alloc.assigned, alloc.client);
+            alloc.forever = false;
+            released.push(alloc.assigned);
+        }
+        self.changed.extend(released);
+
+        for alloc in foreign {
+            info!("Dropping allocation of reserved address {} for {:?}", alloc.assigned, alloc.client);
+            self.leases.retain(|l| l.assigned != alloc.assigned);
+            self.touch(alloc.assigned);
+            self.del_alloc(alloc);
+        }
+    }
+
+    /// Get the allocation of `client` for its reserved address `addr`, which is never reclaimed.
+    /// Other allocations of the client and other clients on `addr` are dropped.
+    pub fn get_reserved(&mut self, client: &lease::Client<EthernetAddr>, addr: Ipv4Addr) -> Option<&lease::Allocation<EthernetAddr, Ipv4Addr>> {
+        let (keep, stale): (Vec<_>, Vec<_>) = self.allocations.drain(..)
+            .partition(|alloc| client.overlapping(&alloc.client) == (alloc.assigned == addr));
+        self.allocations = keep;
+
+        for alloc in stale {
+            self.leases.retain(|l| l.assigned != alloc.assigned);
+            self.touch(alloc.assigned);
+            // Reserved and quarantined addresses must not be handed out again
+            if alloc.assigned != addr && !alloc.forever && !self.quarantined.iter().any(|q| q.assigned == alloc.assigned) {
+                self.address_pool.set_unused(&alloc.assigned);
+            }
+            self.del_alloc(alloc);
+        }
+
+        let index = match self.allocations.iter().position(|alloc| alloc.assigned == addr) {
+                Some(x) => x,
+                None => {
+                    info!("Creating reserved allocation for {:?} on ip {}", client, addr);
+                    self.address_pool.set_used(&addr);
+                    let alloc = self.make_alloc(addr, client.clone());
+                    self.allocations.push(alloc);
+                    self.allocations.len() - 1
+                },
+            };
+
+        if !self.allocations[index].forever {
+            self.allocations[index].forever = true;
+            self.touch(addr);
+        }
+        Some(&self.allocations[index])
+    }
+
+    /// Check whether `client` holds an active lease on `addr`
+    pub fn is_leased(&self, client: &lease::Client<EthernetAddr>, addr: Ipv4Addr) -> bool {
+        self.leases.iter().any(|l| l.assigned == addr && l.is_active() && client.overlapping(&l.client))
+    }
+
     // We *may* be out of allocatable addresses
     fn allocation_for(&mut self, client: &lease::Client<EthernetAddr>) -> Option<&mut lease::Allocation<EthernetAddr, Ipv4Addr>> {
         trace!("Getting generated allocation");
@@ -153,6 +322,7 @@ impl Allocator {
             self.next_ip().map(|ip| {
                 info!("Creating allocation for {:?} on ip {}", client, &ip);
                 let alloc = self.make_alloc(ip, client.clone());
+                self.touch(ip);
                 self.allocations.push(alloc);
                 self.allocations.len() - 1
             })
@@ -176,11 +346,26 @@ impl Allocator {
                              lease_time: u32)
                              -> Option<&lease::Lease<EthernetAddr, Ipv4Addr>> {
         let hook = self.lease_hook.clone();
-        if let Some(a) = self.get_allocation_mut(client, addr) {
+        let seen = self.get_allocation_mut(client, addr).map(|a| {
             a.last_seen = lease::SerializeableTime(time::get_time());
+            a.assigned
+        });
+        if let Some(x) = seen {
+            self.touch(x);
         }
 
-        self.get_lease_mut(client, addr, lease_time).map(|l| { Self::renew_lease(&hook, l); &*l })
+        let leased = self.get_lease_mut(client, addr, lease_time).map(|l| {
+            l.lease_duration = lease_time;
+            Self::renew_lease(&hook, l);
+            l.assigned
+        });
+        match leased {
+            Some(x) => {
+                self.touch(x);
+                self.leases.iter().find(|l| l.assigned == x)
+            },
+            None => None,
+        }
     }
 
     fn get_lease_mut(&mut self,
@@ -214,6 +399,7 @@ impl Allocator {
                     && !self.address_pool.is_used(addr) {
                 info!("Creating requested allocation for {:?} on ip {}", client, addr);
                 self.address_pool.set_used(addr);
+                self.touch(*addr);
                 let alloc = self.make_alloc(*addr, client.clone());
                 self.allocations.push(alloc);
                 Some(self.allocations.len() - 1)
@@ -237,14 +423,6 @@ impl Allocator {
         self.get_allocation_mut(client, addr).map(|x| &*x)
     }
 
-    fn serialize_leases(&self) -> String {
-        serde_json::to_string(&self.leases).unwrap()
-    }
-
-    fn serialize_allocs(&self) -> String {
-        serde_json::to_string(&self.allocations).unwrap()
-    }
-
     fn ensure_alloc(&mut self, lease: &lease::Lease<EthernetAddr, Ipv4Addr>) -> Result<()> {
         match self.get_allocation(&lease.client, Some(lease.assigned)) {
             Some(_) => Ok(()),
@@ -255,29 +433,39 @@ impl Allocator {
         }
     }
 
-    fn deserialize_leases(&mut self, leases: &str) -> Result<()> {
-        let leases: Vec<lease::Lease<EthernetAddr, Ipv4Addr>> = serde_json::from_str(leases)?;
-
-        for lease in &leases {
-            self.ensure_alloc(lease)?;
+    /// Take over the state read from a store.
+    /// Leases without an allocation get one, expired leases and quarantines are dropped.
+    /// So is everything outside our range, the range may have changed since the state was saved.
+    fn load_state(&mut self, mut state: store::State) -> Result<()> {
+        let count = state.allocations.len() + state.leases.len() + state.quarantined.len();
+        state.allocations.retain(|a| self.address_pool.is_suitable(&a.assigned));
+        state.leases.retain(|l| self.address_pool.is_suitable(&l.assigned));
+        state.quarantined.retain(|q| self.address_pool.is_suitable(&q.assigned));
+        let dropped = count - state.allocations.len() - state.leases.len() - state.quarantined.len();
+        if dropped > 0 {
+            warn!("Dropping {} saved entries of {} that are outside of {}", dropped, self.get_name(), self.get_range_name());
         }
 
-        self.leases = leases.into_iter().filter(|l| l.is_active()).collect();
-
-        Ok(())
-    }
-
-    fn deserialize_allocs(&mut self, allocs: &str) -> Result<()> {
-        self.allocations = serde_json::from_str(allocs)?;
-
+        self.allocations = state.allocations;
         for alloc in &self.allocations {
             self.address_pool.set_used(&alloc.assigned);
         }
 
+        for lease in &state.leases {
+            self.ensure_alloc(lease)?;
+        }
+        self.leases = state.leases.into_iter().filter(|l| l.is_active()).collect();
+
+        self.quarantined = state.quarantined.into_iter().filter(|q| q.is_active()).collect();
+        for q in &self.quarantined {
+            self.address_pool.set_used(&q.assigned);
+        }
+
         Ok(())
     }
 
     fn provide_ip(&mut self) -> Option<(Ipv4Addr, bool)> {
+        self.end_quarantines();
         let pooled = self.address_pool.next().map(|i| (i, false));
         pooled.or_else( || {
 
@@ -303,6 +491,7 @@ impl Allocator {
             let index = self.allocations.iter().position(|alloc| alloc.assigned == i).unwrap();
             let alloc = self.allocations.swap_remove(index);
             self.del_alloc(alloc);
+            self.touch(i);
 
             Some(i)
         } else {
@@ -311,78 +500,222 @@ impl Allocator {
     }
 
     pub fn get_name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| self.get_range_name())
+    }
+
+    pub fn set_name(&mut self, name: String) {
+        self.name = Some(name);
+    }
+
+    pub fn has_own_name(&self) -> bool {
+        self.name.is_some()
+    }
+
+    /// The name of pools without their own name, the ranges like `10.0.0.10-10.0.0.20_10.0.0.30-10.0.0.40`
+    pub fn get_range_name(&self) -> String {
         self.address_pool.get_name()
     }
 
-    fn read_leases(&mut self, my_dir: &std::path::Path) -> Result<()> {
-        let lease_file = my_dir.join("leases.json");
-        let mut lease = std::fs::File::open(lease_file)?;
-        let mut lease_str = String::new();
-        lease.read_to_string(&mut lease_str)?;
-        self.deserialize_leases(lease_str.as_str())?;
-        Ok(())
+    /// Whether `name` is the name of a range that shares addresses with ours
+    pub fn overlaps_range_name(&self, name: &str) -> bool {
+        name.split('_').any(|range| {
+                let mut bounds = range.splitn(2, '-').map(Ipv4Addr::from_str);
+                match (bounds.next(), bounds.next()) {
+                    (Some(Ok(lower)), Some(Ok(upper))) => self.address_pool.overlaps(&lower, &upper),
+                    _ => false,
+                }
+            })
     }
 
-    fn read_allocs(&mut self, my_dir: &std::path::Path) -> Result<()> {
-        let alloc_file = my_dir.join("allocations.json");
-        let mut alloc = std::fs::File::open(alloc_file)?;
-        let mut alloc_str = String::new();
-        alloc.read_to_string(&mut alloc_str)?;
-        self.deserialize_allocs(alloc_str.as_str())?;
-        Ok(())
+    /// Forget everything we read, so we can try again
+    fn clear(&mut self) {
+        for alloc in self.allocations.drain(..) {
+            self.address_pool.set_unused(&alloc.assigned);
+        }
+        for q in self.quarantined.drain(..) {
+            self.address_pool.set_unused(&q.assigned);
+        }
+        self.leases.clear();
     }
 
-    pub fn read_from(&mut self, dir: &std::path::Path) -> Result<()> {
-        let my_dir = dir.join(self.get_name());
-        if !(my_dir.exists() && my_dir.is_dir()){
-            return Err(Error::new(ErrorKind::NotFound, format!("Couldn't find directory {} while trying to read allocator from file", my_dir.to_string_lossy())));
+    pub fn read_from(&mut self, store: &mut store::LeaseStore) -> Result<()> {
+        let state = store.read(&self.get_name())?;
+        self.load_state(state)
+    }
+
+    /// Read the state of the save before the last one, for when the last one is damaged
+    pub fn read_backup_from(&mut self, store: &mut store::LeaseStore) -> Result<()> {
+        self.clear();
+        let state = store.read_backup(&self.get_name())?;
+        let ret = self.load_state(state);
+        if ret.is_err() {
+            self.clear();
         }
 
-        self.read_allocs(my_dir.as_path()).or_else(|e| if e.kind() == ErrorKind::NotFound { Ok(()) } else { Err(e) })?;
-        self.read_leases(my_dir.as_path()).or_else(|e| if e.kind() == ErrorKind::NotFound { Ok(()) } else { Err(e) })?;
+        ret
+    }
 
+    /// Save the changes since the last save as journal entries.
+    /// Once there were enough of them, the whole state is written instead.
+    pub fn save_to(&mut self, store: &mut store::LeaseStore) -> Result<()> {
+        let count = match self.journaled {
+                Some(x) if x < journal::COMPACT_AFTER => x,
+                _ => return self.write_snapshot(store),
+            };
+        self.changed.sort();
+        self.changed.dedup();
+        if self.changed.is_empty() {
+            return Ok(());
+        }
+
+        let mut entries = Vec::with_capacity(self.changed.len() * 3);
+        for addr in &self.changed {
+            self.push_entries(*addr, &mut entries);
+        }
+        if let Err(e) = store.append(&self.get_name(), &entries) {
+            error!("Failed to append the changes of {}, writing everything: {}", self.get_name(), e);
+            return self.write_snapshot(store);
+        }
+
+        self.changed.clear();
+        self.journaled = Some(count + entries.len());
         Ok(())
     }
 
-    fn write_leases(&self, my_dir: &std::path::Path) -> Result<()> {
-        let lease_file = my_dir.join("leases.json");
-        let mut leases = std::fs::File::create(lease_file)?;
-        leases.write_all(self.serialize_leases().as_bytes())?;
+    /// The entries that set `addr` to its current state
+    fn push_entries(&self, addr: Ipv4Addr, entries: &mut Vec<journal::Entry>) {
+        entries.push(match self.allocations.iter().find(|a| a.assigned == addr) {
+                Some(a) => journal::Entry::Allocate(a.clone()),
+                None => journal::Entry::Deallocate(addr),
+            });
+        entries.push(match self.leases.iter().find(|l| l.assigned == addr) {
+                Some(l) => journal::Entry::Lease(l.clone()),
+                None => journal::Entry::Release(addr),
+            });
+        entries.push(match self.quarantined.iter().find(|q| q.assigned == addr) {
+                Some(q) => journal::Entry::Quarantine(q.clone()),
+                None => journal::Entry::Unquarantine(addr),
+            });
+    }
+
+    /// Write the whole state and start a new journal
+    fn write_snapshot(&mut self, store: &mut store::LeaseStore) -> Result<()> {
+        self.journaled = None;
+        let state = store::State {
+            allocations: self.allocations.clone(),
+            leases: self.leases.clone(),
+            quarantined: self.quarantined.clone(),
+        };
+        store.write(&self.get_name(), &state)?;
+        self.changed.clear();
+        self.journaled = Some(0);
 
         Ok(())
     }
 
-    fn write_allocs(&self, my_dir: &std::path::Path) -> Result<()> {
-        let alloc_file = my_dir.join("allocations.json");
-        let mut allocs = std::fs::File::create(alloc_file)?;
-        allocs.write_all(self.serialize_allocs().as_bytes())?;
+    /// Forget the allocation at `index` with its lease, without running the hooks
+    fn drop_allocation(&mut self, index: usize) {
+        let alloc = self.allocations.swap_remove(index);
+        self.leases.retain(|l| l.assigned != alloc.assigned);
+        self.address_pool.set_unused(&alloc.assigned);
+        self.touch(alloc.assigned);
+    }
+
+    /// Take over an allocation another server made, with a lease of `duration` seconds if it has one.
+    /// Entries have to come oldest first, an address or client only moves to a more recent entry.
+    pub fn import(&mut self,
+                  client: &lease::Client<EthernetAddr>,
+                  addr: Ipv4Addr,
+                  start: time::Timespec,
+                  duration: Option<u32>) -> std::result::Result<(), String> {
+        if !self.in_range(addr) {
+            return Err(format!("{} isn't in {}", addr, self.get_range_name()));
+        }
+        if self.quarantined.iter().any(|q| q.assigned == addr) {
+            return Err(format!("{} is quarantined", addr));
+        }
+
+        // Hostnames aren't unique enough to move allocations around
+        let same_client = |alloc: &lease::Allocation<EthernetAddr, Ipv4Addr>|
+            alloc.client.hw_addr == client.hw_addr
+                || (client.client_identifier.is_some() && alloc.client.client_identifier == client.client_identifier);
+
+        if let Some(i) = self.allocations.iter().position(|a| a.assigned == addr && !same_client(a)) {
+            if self.allocations[i].forever || self.allocations[i].last_seen.0 > start {
+                return Err(format!("{} is allocated to {}", addr, self.allocations[i].client.hw_addr));
+            }
+            self.drop_allocation(i);
+        }
+        if let Some(i) = self.allocations.iter().position(|a| a.assigned != addr && same_client(a)) {
+            if self.allocations[i].forever || self.allocations[i].last_seen.0 > start {
+                return Err(format!("{} already has {}", client.hw_addr, self.allocations[i].assigned));
+            }
+            self.drop_allocation(i);
+        }
+
+        // A reservation for the client stays one
+        let forever = self.allocations.iter().any(|a| a.assigned == addr && a.forever);
+        self.allocations.retain(|a| a.assigned != addr);
+        self.allocations.push(lease::Allocation {
+            assigned: addr,
+            client: client.clone(),
+            last_seen: lease::SerializeableTime(start),
+            forever: forever,
+        });
+        self.address_pool.set_used(&addr);
+        self.touch(addr);
+
+        self.leases.retain(|l| l.assigned != addr);
+        if let Some(duration) = duration {
+            let lease = lease::Lease { assigned: addr, client: client.clone(), lease_start: lease::SerializeableTime(start), lease_duration: duration };
+            if lease.is_active() {
+                self.leases.push(lease);
+            }
+        }
 
         Ok(())
     }
 
-    pub fn save_to(&self, dir: &std::path::Path) -> Result<()> {
-        let mut ret = Ok(());
-        if !(dir.exists() && dir.is_dir()){
-            return Err(Error::new(ErrorKind::NotFound, format!("Couldn't find directory {} while trying to write allocator to file", dir.to_string_lossy())));
+    /// Move the state for addresses in our range out of `old`.
+    /// Used when the range of a pool changed on reload, we keep what we already know about an address.
+    pub fn adopt(&mut self, old: &mut Allocator) {
+        let (allocs, rest): (Vec<_>, Vec<_>) = old.allocations.drain(..).partition(|alloc| self.in_range(alloc.assigned));
+        old.allocations = rest;
+        for alloc in allocs {
+            if !self.allocations.iter().any(|a| a.assigned == alloc.assigned) {
+                self.address_pool.set_used(&alloc.assigned);
+                self.touch(alloc.assigned);
+                self.allocations.push(alloc);
+            }
         }
 
-        let my_dir = dir.join(self.get_name());
-        std::fs::create_dir_all(my_dir.as_path())?;
-
-        let _ = self.write_leases(my_dir.as_path()).map_err(|e| {
-                error!("Failed to write leases to file: {}", e);
-                ret = Err(e);
-                ()
-            });
+        let (leases, rest): (Vec<_>, Vec<_>) = old.leases.drain(..).partition(|l| self.in_range(l.assigned));
+        old.leases = rest;
+        for lease in leases {
+            if !self.leases.iter().any(|l| l.assigned == lease.assigned) {
+                self.touch(lease.assigned);
+                self.leases.push(lease);
+            }
+        }
 
-        let _ = self.write_allocs(my_dir.as_path()).map_err(|e| {
-                error!("Failed to write allocations to file: {}", e);
-                ret = Err(e);
-                ()
-            });
+        let (quarantined, rest): (Vec<_>, Vec<_>) = old.quarantined.drain(..).partition(|q| self.in_range(q.assigned));
+        old.quarantined = rest;
+        for q in quarantined {
+            if !self.quarantined.iter().any(|x| x.assigned == q.assigned) {
+                self.address_pool.set_used(&q.assigned);
+                self.touch(q.assigned);
+                self.quarantined.push(q);
+            }
+        }
+    }
 
+    /// Whether there's anything we'd lose when dropping the allocator
+    pub fn is_empty(&self) -> bool {
+        self.allocations.is_empty() && self.leases.is_empty() && self.quarantined.is_empty()
+    }
 
-        ret
+    pub fn in_range(&self, addr: Ipv4Addr) -> bool {
+        self.address_pool.is_suitable(&addr)
     }
 
     pub fn get_bounds(&self) -> (Ipv4Addr, Ipv4Addr) {
@@ -393,10 +726,15 @@ impl Allocator {
 #[cfg(test)]
 mod test {
     use super::Allocator;
+    use std;
+    use std::io::Write;
     use pool::GPool;
     use std::net::Ipv4Addr;
     use frame::ethernet::EthernetAddr;
     use lease;
+    use store;
+    use store::json::JsonStore;
+    use time;
 
     #[test]
     fn gets_first() {
@@ -452,6 +790,22 @@ mod test {
         assert!(alloc.get_allocation(&client2, Some(Ipv4Addr::new(0, 0, 0, 0))).is_none());
     }
 
+    #[test]
+    fn knows_allocation() {
+        let mut alloc = Allocator::new(GPool::new(Ipv4Addr::new(0, 0, 0, 0), Ipv4Addr::new(0, 0, 0, 5)).unwrap(), None, None, None);
+        let client = lease::Client{client_identifier: None, hostname: None, hw_addr: EthernetAddr([0, 0, 0, 0, 0, 0])};
+        let client2 = lease::Client{client_identifier: None, hostname: None, hw_addr: EthernetAddr([0, 0, 0, 0, 0, 1])};
+
+        assert!(alloc.get_allocated(&client).is_none());
+        let _ = alloc.get_allocation(&client, Some(Ipv4Addr::new(0, 0, 0, 2)));
+
+        assert!(alloc.get_allocated(&client) == Some(Ipv4Addr::new(0, 0, 0, 2)));
+        assert!(alloc.has_allocation(&client, Ipv4Addr::new(0, 0, 0, 2)));
+        assert!(!alloc.has_allocation(&client, Ipv4Addr::new(0, 0, 0, 3)));
+        assert!(!alloc.has_allocation(&client2, Ipv4Addr::new(0, 0, 0, 2)));
+        assert!(alloc.get_allocated(&client2).is_none());
+    }
+
     #[test]
     fn denies_unsuitable() {
         let mut alloc = Allocator::new(GPool::new(Ipv4Addr::new(0, 0, 0, 0), Ipv4Addr::new(0, 0, 0, 5)).unwrap(), None, None, None);
@@ -489,4 +843,294 @@ mod test {
 
         assert!(alloc.get_allocation(&client4, None).map(|a| a.assigned == Ipv4Addr::new(0, 0, 0, 1)).unwrap_or(false));
     }
+
+    #[test]
+    fn skips_declined() {
+        let mut alloc = Allocator::new(GPool::new(Ipv4Addr::new(0, 0, 0, 0), Ipv4Addr::new(0, 0, 0, 5)).unwrap(), None, None, None);
+        let client = lease::Client{client_identifier: None, hostname: None, hw_addr: EthernetAddr([0, 0, 0, 0, 0, 0])};
+
+        let _ = alloc.get_renewed_lease(&client, None, 7200);
+        alloc.decline(&client, Ipv4Addr::new(0, 0, 0, 0), 3600);
+
+        assert!(alloc.leases.is_empty());
+        assert!(alloc.get_allocation(&client, None).map(|a| a.assigned == Ipv4Addr::new(0, 0, 0, 1)).unwrap_or(false));
+    }
+
+    #[test]
+    fn ignores_foreign_decline() {
+        let mut alloc = Allocator::new(GPool::new(Ipv4Addr::new(0, 0, 0, 0), Ipv4Addr::new(0, 0, 0, 5)).unwrap(), None, None, None);
+        let client = lease::Client{client_identifier: None, hostname: None, hw_addr: EthernetAddr([0, 0, 0, 0, 0, 0])};
+        let client2 = lease::Client{client_identifier: None, hostname: None, hw_addr: EthernetAddr([0, 0, 0, 0, 0, 1])};
+
+        let _ = alloc.get_allocation(&client, None);
+        alloc.decline(&client2, Ipv4Addr::new(0, 0, 0, 0), 3600);
+
+        assert!(alloc.quarantined.is_empty());
+        assert!(alloc.get_allocation(&client, None).map(|a| a.assigned == Ipv4Addr::new(0, 0, 0, 0)).unwrap_or(false));
+    }
+
+    #[test]
+    fn drops_vendor_class_as_identifier() {
+        let mut alloc = Allocator::new(GPool::new(Ipv4Addr::new(0, 0, 0, 0), Ipv4Addr::new(0, 0, 0, 5)).unwrap(), None, None, None);
+        let old = r#"[{"assigned":"0.0.0.2","client":{"hw_addr":[0,0,0,0,0,1],"client_identifier":[77,83,70,84],"hostname":null},"last_seen":[0,0]}]"#;
+        let client = lease::Client{client_identifier: None, hostname: None, hw_addr: EthernetAddr([0, 0, 0, 0, 0, 1])};
+
+        alloc.load_state(store::State { allocations: store::format::decode(old).unwrap(), leases: Vec::new(), quarantined: Vec::new() }).unwrap();
+
+        assert!(alloc.allocations[0].client == client);
+    }
+
+    #[test]
+    fn keeps_reserved() {
+        let mut alloc = Allocator::new(GPool::new(Ipv4Addr::new(0, 0, 0, 0), Ipv4Addr::new(0, 0, 0, 5)).unwrap(), None, None, None);
+        let client = lease::Client{client_identifier: None, hostname: None, hw_addr: EthernetAddr([0, 0, 0, 0, 0, 0])};
+        let client2 = lease::Client{client_identifier: None, hostname: None, hw_addr: EthernetAddr([0, 0, 0, 0, 0, 1])};
+        let reserved = Ipv4Addr::new(0, 0, 0, 0);
+
+        let _ = alloc.get_allocation(&client2, None);
+        alloc.reserve(&[reserved], |c, _| c.hw_addr == client.hw_addr);
+
+        assert!(!alloc.has_allocation(&client2, reserved));
+        assert!(alloc.get_allocation(&client2, None).map(|a| a.assigned != reserved).unwrap_or(false));
+        assert!(alloc.get_reserved(&client, reserved).map(|a| a.assigned == reserved && a.forever).unwrap_or(false));
+    }
+
+    #[test]
+    fn drops_offer_taken_elsewhere() {
+        let mut alloc = Allocator::new(GPool::new(Ipv4Addr::new(0, 0, 0, 0), Ipv4Addr::new(0, 0, 0, 5)).unwrap(), None, None, None);
+        let client = lease::Client{client_identifier: None, hostname: None, hw_addr: EthernetAddr([0, 0, 0, 0, 0, 0])};
+        let client2 = lease::Client{client_identifier: None, hostname: None, hw_addr: EthernetAddr([0, 0, 0, 0, 0, 1])};
+
+        let offered = alloc.get_allocation(&client, None).unwrap().assigned;
+        alloc.drop_offer(&client);
+        assert!(!alloc.has_allocation(&client, offered));
+        assert!(!alloc.address_pool.is_used(&offered));
+
+        let leased = alloc.get_renewed_lease(&client2, None, 3600).unwrap().assigned;
+        alloc.drop_offer(&client2);
+        assert!(alloc.is_leased(&client2, leased));
+    }
+
+    #[test]
+    fn keeps_reserved_out_of_pool() {
+        let mut alloc = Allocator::new(GPool::new(Ipv4Addr::new(0, 0, 0, 0), Ipv4Addr::new(0, 0, 0, 5)).unwrap(), None, None, None);
+        let client = lease::Client{client_identifier: None, hostname: None, hw_addr: EthernetAddr([0, 0, 0, 0, 0, 0])};
+        let old = Ipv4Addr::new(0, 0, 0, 1);
+        let new = Ipv4Addr::new(0, 0, 0, 2);
+
+        let _ = alloc.get_reserved(&client, old);
+        alloc.reserve(&[old, new], |c, addr| addr == old && c.hw_addr == client.hw_addr);
+        let _ = alloc.get_reserved(&client, new);
+
+        assert!(alloc.address_pool.is_used(&old));
+
+        let _ = alloc.get_reserved(&client, old);
+        alloc.reserve(&[], |_, _| false);
+
+        assert!(alloc.allocations.iter().all(|a| !a.forever));
+    }
+
+    #[test]
+    fn skips_conflicting() {
+        let mut alloc = Allocator::new(GPool::new(Ipv4Addr::new(0, 0, 0, 0), Ipv4Addr::new(0, 0, 0, 5)).unwrap(), None, None, None);
+        let client = lease::Client{client_identifier: None, hostname: None, hw_addr: EthernetAddr([0, 0, 0, 0, 0, 0])};
+
+        let first = alloc.get_allocation(&client, None).unwrap().assigned;
+        alloc.conflict(first, 3600);
+
+        assert!(alloc.quarantined.len() == 1);
+        assert!(alloc.get_allocation(&client, None).map(|a| a.assigned != first).unwrap_or(false));
+    }
+
+    #[test]
+    fn adopts_in_range() {
+        let mut old = Allocator::new(GPool::new(Ipv4Addr::new(0, 0, 0, 0), Ipv4Addr::new(0, 0, 0, 9)).unwrap(), None, None, None);
+        let client = lease::Client{client_identifier: None, hostname: None, hw_addr: EthernetAddr([0, 0, 0, 0, 0, 0])};
+        let client2 = lease::Client{client_identifier: None, hostname: None, hw_addr: EthernetAddr([0, 0, 0, 0, 0, 1])};
+        let first = old.get_renewed_lease(&client, None, 3600).unwrap().assigned;
+        let second = old.get_renewed_lease(&client2, None, 3600).unwrap().assigned;
+
+        let mut new = Allocator::new(GPool::new(first, first).unwrap(), None, None, None);
+        new.adopt(&mut old);
+
+        assert!(new.is_leased(&client, first));
+        assert!(!new.is_leased(&client2, second));
+        assert!(old.is_leased(&client2, second) && !old.is_leased(&client, first));
+        assert!(new.get_allocation(&client2, None).is_none());
+    }
+
+    #[test]
+    fn keeps_state_under_name() {
+        let dir = std::env::temp_dir().join(format!("dhcpd-named-{}", std::process::id()));
+        let mut store = JsonStore::new(dir.clone());
+        let client = lease::Client{client_identifier: None, hostname: None, hw_addr: EthernetAddr([0, 0, 0, 0, 0, 0])};
+        let client2 = lease::Client{client_identifier: None, hostname: None, hw_addr: EthernetAddr([0, 0, 0, 0, 0, 1])};
+
+        let mut alloc = Allocator::new(GPool::new(Ipv4Addr::new(0, 0, 0, 0), Ipv4Addr::new(0, 0, 0, 5)).unwrap(), None, None, None);
+        alloc.set_name(String::from("lan"));
+        let first = alloc.get_renewed_lease(&client, None, 3600).unwrap().assigned;
+        let second = alloc.get_renewed_lease(&client2, None, 3600).unwrap().assigned;
+        alloc.save_to(&mut store).unwrap();
+
+        let mut read = Allocator::new(GPool::new(Ipv4Addr::new(0, 0, 0, 1), Ipv4Addr::new(0, 0, 0, 9)).unwrap(), None, None, None);
+        read.set_name(String::from("lan"));
+        read.read_from(&mut store).unwrap();
+        assert!(!read.has_allocation(&client, first));
+        assert!(read.is_leased(&client2, second));
+        assert!(read.overlaps_range_name(&alloc.get_range_name()));
+        assert!(!read.overlaps_range_name("0.0.0.10-0.0.0.20"));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn imports_latest() {
+        let mut alloc = Allocator::new(GPool::new(Ipv4Addr::new(0, 0, 0, 0), Ipv4Addr::new(0, 0, 0, 5)).unwrap(), None, None, None);
+        let client = lease::Client{client_identifier: None, hostname: None, hw_addr: EthernetAddr([0, 0, 0, 0, 0, 0])};
+        let client2 = lease::Client{client_identifier: None, hostname: None, hw_addr: EthernetAddr([0, 0, 0, 0, 0, 1])};
+        let now = time::get_time();
+        let old = time::Timespec::new(now.sec - 7200, 0);
+
+        alloc.import(&client, Ipv4Addr::new(0, 0, 0, 1), old, Some(3600)).unwrap();
+        alloc.import(&client, Ipv4Addr::new(0, 0, 0, 2), now, Some(3600)).unwrap();
+        assert!(alloc.import(&client2, Ipv4Addr::new(0, 0, 0, 2), old, None).is_err());
+        assert!(alloc.import(&client2, Ipv4Addr::new(0, 0, 0, 9), now, None).is_err());
+
+        assert!(alloc.get_allocated(&client) == Some(Ipv4Addr::new(0, 0, 0, 2)));
+        assert!(alloc.is_leased(&client, Ipv4Addr::new(0, 0, 0, 2)));
+        assert!(!alloc.has_allocation(&client, Ipv4Addr::new(0, 0, 0, 1)));
+        assert!(!alloc.address_pool.is_used(&Ipv4Addr::new(0, 0, 0, 1)));
+    }
+
+    #[test]
+    fn recovers_from_backup() {
+        let dir = std::env::temp_dir().join(format!("dhcpd-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut store = JsonStore::new(dir.clone());
+        let pool = || GPool::new(Ipv4Addr::new(0, 0, 0, 0), Ipv4Addr::new(0, 0, 0, 5)).unwrap();
+        let client = lease::Client{client_identifier: None, hostname: None, hw_addr: EthernetAddr([0, 0, 0, 0, 0, 0])};
+        let client2 = lease::Client{client_identifier: None, hostname: None, hw_addr: EthernetAddr([0, 0, 0, 0, 0, 1])};
+
+        let mut alloc = Allocator::new(pool(), None, None, None);
+        let first = alloc.get_renewed_lease(&client, None, 3600).unwrap().assigned;
+        alloc.save_to(&mut store).unwrap();
+        let _ = alloc.get_renewed_lease(&client2, None, 3600);
+        // Force a second snapshot instead of a journal entry
+        alloc.journaled = None;
+        alloc.save_to(&mut store).unwrap();
+
+        let my_dir = dir.join(alloc.get_name());
+        std::fs::File::create(my_dir.join("state.json")).unwrap().write_all(b"{\"version\":1,\"allocations\":[{").unwrap();
+
+        let mut read = Allocator::new(pool(), None, None, None);
+        assert!(read.read_from(&mut store).is_err());
+        assert!(read.read_backup_from(&mut store).is_ok());
+        assert!(read.is_leased(&client, first));
+        assert!(read.get_allocated(&client2).is_none());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn reads_separate_files() {
+        let dir = std::env::temp_dir().join(format!("dhcpd-legacy-{}", std::process::id()));
+        let mut store = JsonStore::new(dir.clone());
+        let pool = || GPool::new(Ipv4Addr::new(0, 0, 0, 0), Ipv4Addr::new(0, 0, 0, 5)).unwrap();
+        let client = lease::Client{client_identifier: None, hostname: None, hw_addr: EthernetAddr([0, 0, 0, 0, 0, 1])};
+
+        let mut alloc = Allocator::new(pool(), None, None, None);
+        let my_dir = dir.join(alloc.get_name());
+        std::fs::create_dir_all(&my_dir).unwrap();
+        std::fs::File::create(my_dir.join("allocations.json")).unwrap()
+            .write_all(br#"[{"assigned":"0.0.0.2","client":{"hw_addr":[0,0,0,0,0,1],"client_identifier":null,"hostname":null},"last_seen":[0,0]}]"#).unwrap();
+
+        alloc.read_from(&mut store).unwrap();
+        assert!(alloc.has_allocation(&client, Ipv4Addr::new(0, 0, 0, 2)));
+
+        alloc.save_to(&mut store).unwrap();
+        assert!(my_dir.join("state.json").exists() && !my_dir.join("allocations.json").exists());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn replays_journal() {
+        let dir = std::env::temp_dir().join(format!("dhcpd-journal-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut store = JsonStore::new(dir.clone());
+        let pool = || GPool::new(Ipv4Addr::new(0, 0, 0, 0), Ipv4Addr::new(0, 0, 0, 5)).unwrap();
+        let client = lease::Client{client_identifier: None, hostname: None, hw_addr: EthernetAddr([0, 0, 0, 0, 0, 0])};
+        let client2 = lease::Client{client_identifier: None, hostname: None, hw_addr: EthernetAddr([0, 0, 0, 0, 0, 1])};
+        let client3 = lease::Client{client_identifier: None, hostname: None, hw_addr: EthernetAddr([0, 0, 0, 0, 0, 2])};
+
+        let mut alloc = Allocator::new(pool(), None, None, None);
+        let first = alloc.get_renewed_lease(&client, None, 3600).unwrap().assigned;
+        alloc.save_to(&mut store).unwrap();
+        let second = alloc.get_renewed_lease(&client2, None, 3600).unwrap().assigned;
+        alloc.free_lease(&client, first);
+        let third = alloc.get_allocation(&client3, None).unwrap().assigned;
+        alloc.decline(&client3, third, 3600);
+        alloc.save_to(&mut store).unwrap();
+
+        let journal = dir.join(alloc.get_name()).join("journal.json");
+        assert!(journal.exists());
+        std::fs::OpenOptions::new().append(true).open(&journal).unwrap().write_all(b"{\"Rele").unwrap();
+
+        let mut read = Allocator::new(pool(), None, None, None);
+        read.read_from(&mut store).unwrap();
+        assert!(read.is_leased(&client2, second));
+        assert!(!read.is_leased(&client, first));
+        assert!(read.has_allocation(&client, first));
+        assert!(!read.has_allocation(&client3, third) && read.quarantined.iter().any(|q| q.assigned == third));
+
+        read.save_to(&mut store).unwrap();
+        assert!(!journal.exists());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn skips_stale_journal() {
+        let dir = std::env::temp_dir().join(format!("dhcpd-stale-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut store = JsonStore::new(dir.clone());
+        let pool = || GPool::new(Ipv4Addr::new(0, 0, 0, 0), Ipv4Addr::new(0, 0, 0, 5)).unwrap();
+        let client = lease::Client{client_identifier: None, hostname: None, hw_addr: EthernetAddr([0, 0, 0, 0, 0, 0])};
+        let client2 = lease::Client{client_identifier: None, hostname: None, hw_addr: EthernetAddr([0, 0, 0, 0, 0, 1])};
+
+        let mut alloc = Allocator::new(pool(), None, None, None);
+        let first = alloc.get_renewed_lease(&client, None, 3600).unwrap().assigned;
+        alloc.save_to(&mut store).unwrap();
+        let second = alloc.get_renewed_lease(&client2, None, 3600).unwrap().assigned;
+        alloc.save_to(&mut store).unwrap();
+
+        // Crash after the next snapshot was written, before the journal was removed
+        let journal = dir.join(alloc.get_name()).join("journal.json");
+        let stale = dir.join("journal.json");
+        std::fs::copy(&journal, &stale).unwrap();
+        alloc.free_lease(&client2, second);
+        alloc.journaled = None;
+        alloc.save_to(&mut store).unwrap();
+        std::fs::copy(&stale, &journal).unwrap();
+
+        let mut reopened = JsonStore::new(dir.clone());
+        let mut read = Allocator::new(pool(), None, None, None);
+        read.read_from(&mut reopened).unwrap();
+        assert!(read.is_leased(&client, first));
+        assert!(!read.is_leased(&client2, second));
+        assert!(journal.exists());
+
+        // Only the next snapshot replaces it
+        read.save_to(&mut reopened).unwrap();
+        assert!(!journal.exists());
+
+        // The backup is the first snapshot, the journal belongs to the one after it
+        std::fs::copy(&stale, &journal).unwrap();
+        let mut backup = Allocator::new(pool(), None, None, None);
+        backup.read_backup_from(&mut JsonStore::new(dir.clone())).unwrap();
+        assert!(backup.is_leased(&client, first));
+        assert!(!backup.is_leased(&client2, second));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
 }
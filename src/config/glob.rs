@@ -0,0 +1,45 @@
+/// Match `name` against a shell style pattern, ignoring case like DNS does.
+/// `*` matches any number of characters and `?` exactly one.
+pub fn matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    let name: Vec<char> = name.to_lowercase().chars().collect();
+
+    let (mut p, mut n) = (0, 0);
+    // Where we go back to when the rest doesn't match, the last star and what it matched up to
+    let mut backtrack: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, n));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            n = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::matches;
+
+    #[test]
+    fn matches_globs() {
+        assert!(matches("kiosk-*", "kiosk-01"));
+        assert!(matches("kiosk-*", "KIOSK-"));
+        assert!(matches("kiosk-??", "kiosk-42"));
+        assert!(!matches("kiosk-??", "kiosk-100"));
+        assert!(matches("*.lab.example.com", "pc1.lab.example.com"));
+        assert!(!matches("*.lab.example.com", "pc1.example.com"));
+        assert!(matches("a*b*c", "axxbyybc"));
+        assert!(!matches("a*b*c", "axxbyyb"));
+        assert!(matches("*", ""));
+    }
+}
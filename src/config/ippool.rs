@@ -23,6 +23,15 @@ pub enum IPPool {
 }
 
 impl IPPool {
+    /// Check whether `addr` is in the range, `None` if we only know at runtime
+    pub fn contains(&self, addr: Ipv4Addr) -> Option<bool> {
+        match *self {
+            IPPool::Range(ref range) => Some(range.lower <= addr && addr <= range.upper),
+            IPPool::Ranges(ref ranges) => Some(ranges.iter().any(|r| r.lower <= addr && addr <= r.upper)),
+            IPPool::Guess => None,
+        }
+    }
+
     pub fn get_pool(self, name: &str) -> Option<pool::GPool<Ipv4Addr>> {
         match self {
             IPPool::Range(range) => pool::GPool::new(range.lower, range.upper),
@@ -1,62 +1,353 @@
 extern crate rs_config;
 
+mod glob;
 mod ippool;
 use self::ippool::IPPool;
 
 use rs_config::ConfigAble;
 use std::net::Ipv4Addr;
-use ::frame::ethernet::EthernetAddr;
+use ::frame::ethernet::{EthernetAddr, MacPattern};
+use ::packet::{DhcpOption, DhcpPacket, HwAddr, RelaySubOption};
+use ::lease::Client;
 
 use log::LogLevel;
 
+/// How deep classes may reference other classes before we give up
+const MAX_CLASS_DEPTH: usize = 16;
+
 #[derive(Debug, ConfigAble)]
 pub struct IPNet(pub Ipv4Addr, pub u8);
 
-#[derive(Debug, ConfigAble)]
+#[derive(Debug, Clone, ConfigAble)]
 #[ConfigAttrs(default="Selector::All")]
 pub enum Selector {
     All,
-    Macs(Box<[EthernetAddr]>),
+    /// Hardware addresses, `00:1a:2b:*` and `address/mask` match a whole range
+    Macs(Box<[MacPattern]>),
     Hostnames(Box<[String]>),
-    Either(Box<[Selector]>)
+    /// Patterns for the hostname (option 12) like `kiosk-*`, case doesn't matter
+    HostnameGlobs(Box<[String]>),
+    /// Patterns for the fully qualified domain name (option 81) like `*.lab.example.com`
+    Fqdns(Box<[String]>),
+    /// Circuit-ids from the relay agent information (option 82)
+    CircuitIds(Box<[String]>),
+    /// Remote-ids from the relay agent information (option 82)
+    RemoteIds(Box<[String]>),
+    /// Prefixes of the vendor class identifier (option 60), e.g. "MSFT" or "PXEClient"
+    VendorClasses(Box<[String]>),
+    /// Any of the user classes (option 77) is one of these
+    UserClasses(Box<[String]>),
+    /// The content of the option with the code starts with the string
+    OptionPrefix(u8, String),
+    /// The relay agent sub-option (option 82) with the code is the string
+    RelayOption(u8, String),
+    /// The hardware type, from the client identifier if the client sent one
+    HardwareType(u8),
+    /// The client is in the class with this name
+    Class(String),
+    Either(Box<[Selector]>),
+    And(Box<[Selector]>),
+    /// None of the selectors match
+    Not(Box<[Selector]>)
 }
 
 impl Selector {
-    pub fn is_suitable(&self, client: &::lease::Client<::frame::ethernet::EthernetAddr>) -> bool {
+    pub fn is_suitable(&self,
+                       client: &::lease::Client<::frame::ethernet::EthernetAddr>,
+                       request: &DhcpPacket<EthernetAddr>,
+                       classes: &[Class]) -> bool {
+        self.matches(client, request, classes, 0)
+    }
+
+    fn matches(&self,
+               client: &Client<EthernetAddr>,
+               request: &DhcpPacket<EthernetAddr>,
+               classes: &[Class],
+               depth: usize) -> bool {
         match *self {
             Selector::All => true,
-            Selector::Macs(ref b) => b.iter().any(|x| x == &client.hw_addr),
+            Selector::Macs(ref b) => b.iter().any(|x| x.matches(&client.hw_addr)),
             Selector::Hostnames(ref b) => match client.hostname {
                 // Why is this so hard to do with .map()? :(
                     None => false,
                     Some(ref name) => b.iter().any(|x| name == x),
                 }
-            Selector::Either(ref b) => b.iter().any(|s| s.is_suitable(client)),
+            Selector::HostnameGlobs(ref b) => match client.hostname {
+                    None => false,
+                    Some(ref name) => b.iter().any(|x| glob::matches(x, name)),
+                }
+            Selector::Fqdns(ref b) => match request.get_fqdn() {
+                    None => false,
+                    Some(name) => b.iter().any(|x| glob::matches(x, &name)),
+                }
+            Selector::CircuitIds(ref b) => request.get_relay_info().map(|subs| subs.iter().any(|sub| match *sub {
+                    RelaySubOption::CircuitId(ref id) => b.iter().any(|x| x.as_bytes() == &id[..]),
+                    _ => false,
+                })).unwrap_or(false),
+            Selector::RemoteIds(ref b) => request.get_relay_info().map(|subs| subs.iter().any(|sub| match *sub {
+                    RelaySubOption::RemoteId(ref id) => b.iter().any(|x| x.as_bytes() == &id[..]),
+                    _ => false,
+                })).unwrap_or(false),
+            Selector::VendorClasses(ref b) => match request.get_vendor_class() {
+                    None => false,
+                    Some(class) => b.iter().any(|x| class.starts_with(x.as_bytes())),
+                }
+            Selector::UserClasses(ref b) => request.get_user_classes().iter()
+                    .any(|class| b.iter().any(|x| x.as_bytes() == &class[..])),
+            Selector::OptionPrefix(code, ref prefix) => match request.get_option_value(code) {
+                    None => false,
+                    Some(value) => value.starts_with(prefix.as_bytes()),
+                }
+            Selector::RelayOption(code, ref value) => request.get_relay_info().map(|subs| subs.iter()
+                    .any(|sub| sub.get_type() == code && sub.get_value() == value.as_bytes()))
+                    .unwrap_or(false),
+            Selector::HardwareType(hwtype) => match client.client_identifier {
+                    Some(ref id) if !id.is_empty() => id[0] == hwtype,
+                    _ => EthernetAddr::hwtype() == hwtype,
+                }
+            Selector::Class(ref name) => {
+                if depth >= MAX_CLASS_DEPTH {
+                    warn!("Class {} is nested too deep, is there a cycle?", name);
+                    return false;
+                }
+                classes.iter().filter(|class| &class.name == name)
+                    .any(|class| class.selector.matches(client, request, classes, depth + 1))
+            }
+            Selector::Either(ref b) => b.iter().any(|s| s.matches(client, request, classes, depth)),
+            Selector::And(ref b) => b.iter().all(|s| s.matches(client, request, classes, depth)),
+            Selector::Not(ref b) => !b.iter().any(|s| s.matches(client, request, classes, depth)),
+        }
+    }
+
+    /// The names of the classes this selector references
+    fn referenced_classes(&self) -> Vec<&str> {
+        match *self {
+            Selector::Class(ref name) => vec![name.as_str()],
+            Selector::Either(ref b) | Selector::And(ref b) | Selector::Not(ref b) =>
+                b.iter().flat_map(|s| s.referenced_classes()).collect(),
+            _ => Vec::new(),
         }
     }
 }
 
+/// How we make sure an address is unused before offering it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ConfigAble)]
+#[ConfigAttrs(default="Probe::Off")]
+pub enum Probe {
+    Off,
+    /// Send an ICMP echo request and wait this many milliseconds for a reply.
+    /// On the local link we don't know the MAC of the address, the request goes to the broadcast MAC.
+    Ping(u32),
+    /// Send an ARP probe and wait this many milliseconds for a reply.
+    /// ARP doesn't cross routers, relayed clients get a ping instead.
+    Arp(u32),
+}
+
+/// The host a reservation is for
+#[derive(Debug, Clone, ConfigAble)]
+pub enum Host {
+    Mac(EthernetAddr),
+    /// The client identifier (option 61)
+    ClientId(Box<[u8]>),
+    /// The hostname the client sends (option 12), only used if no reservation matches its MAC or client identifier
+    Hostname(String),
+}
+
+impl Host {
+    pub fn matches(&self, client: &Client<EthernetAddr>) -> bool {
+        match *self {
+            Host::Mac(ref mac) => *mac == client.hw_addr,
+            Host::ClientId(ref id) => client.client_identifier.as_ref().map(|x| x == id).unwrap_or(false),
+            Host::Hostname(ref name) => client.hostname.as_ref().map(|x| x == name).unwrap_or(false),
+        }
+    }
+
+    /// Anyone can send any hostname, so it's only trusted if nothing better matches
+    pub fn is_hostname(&self) -> bool {
+        match *self {
+            Host::Hostname(_) => true,
+            _ => false,
+        }
+    }
+}
+
+/// A fixed address for a single host
+#[derive(Debug, Clone, ConfigAble)]
+pub struct Reservation {
+    pub host: Host,
+    pub address: Ipv4Addr,
+    /// Options for this host, they replace the ones of the pool with the same code
+    #[ConfigAttrs(default="Vec::new()")]
+    pub options: Vec<DhcpOption>,
+}
+
 #[derive(Debug, ConfigAble)]
 pub struct Pool {
+    /// Name the state is saved under, the range if not set.
+    /// With a name the leases survive changes of the range.
+    pub name: Option<String>,
     pub selector: Selector,
     pub range: IPPool,
     pub options: Vec<::packet::DhcpOption>,
+    /// Option codes sent even if the client didn't ask for them
+    #[ConfigAttrs(default="Vec::new()")]
+    pub always_send: Vec<u8>,
+
+    /// Seconds a declined address is kept out of the pool
+    pub quarantine: Option<u32>,
+    /// Check offered addresses aren't in use already
+    pub probe: Probe,
+    /// Hosts that always get the same address, it has to be in the range
+    #[ConfigAttrs(default="Vec::new()")]
+    pub reservations: Vec<Reservation>,
 
     pub allocate: Option<String>,
     pub lease: Option<String>,
     pub deallocate: Option<String>,
 }
 
+/// Options for all clients matching `selector`, in any pool.
+/// They override the options of the pool, the ones of a host reservation override them.
+#[derive(Debug, Clone, ConfigAble)]
+pub struct Class {
+    pub name: String,
+    pub selector: Selector,
+    #[ConfigAttrs(default="Vec::new()")]
+    pub options: Vec<DhcpOption>,
+    /// Lease time in seconds, overrides the LeaseTime option
+    pub lease_time: Option<u32>,
+}
+
+impl Class {
+    /// Whether the class references itself, directly or through other classes
+    fn is_cyclic(&self, classes: &[Class]) -> bool {
+        let mut todo: Vec<&str> = self.selector.referenced_classes();
+        let mut seen: Vec<&str> = Vec::new();
+        while let Some(name) = todo.pop() {
+            if name == self.name {
+                return true;
+            }
+            if seen.contains(&name) {
+                continue;
+            }
+            seen.push(name);
+            for class in classes.iter().filter(|c| c.name == name) {
+                todo.extend(class.selector.referenced_classes());
+            }
+        }
+
+        false
+    }
+}
+
 #[derive(Debug, ConfigAble)]
 pub struct Interface {
     pub name: String,
+    /// Options for all pools on the interface, the pools can override them
+    #[ConfigAttrs(default="Vec::new()")]
+    pub options: Vec<DhcpOption>,
+    /// Pools on subnets that aren't attached to the interface are used for relayed requests
     pub pool: Vec<Pool>
 }
 
+/// Where the lease state is kept
+#[derive(Debug, Clone, PartialEq, ConfigAble)]
+#[ConfigAttrs(default="Storage::Json")]
+pub enum Storage {
+    /// JSON files in `cache_dir/<interface>/<pool>`
+    Json,
+    /// A SQLite database at this path, shared by all interfaces
+    Sqlite(String),
+}
+
 #[derive(Debug, ConfigAble)]
 pub struct Config {
     pub log_level: LogLevel,
     #[ConfigAttrs(default="String::from(\"/var/lib/dhcpd\")")]
     pub cache_dir: String,
+    pub storage: Storage,
+    /// Options for every pool, interfaces and pools can override them
+    #[ConfigAttrs(default="Vec::new()")]
+    pub options: Vec<DhcpOption>,
+    /// Classes are checked in order, later ones override earlier ones
+    #[ConfigAttrs(default="Vec::new()")]
+    pub classes: Vec<Class>,
     pub interfaces: Vec<Interface>
 }
+
+impl Config {
+    /// Find problems the parser can't, like reservations outside of their pool
+    pub fn verify(&self) -> Vec<String> {
+        let mut ret = Vec::new();
+        let mut selectors: Vec<&Selector> = self.classes.iter().map(|c| &c.selector).collect();
+        selectors.extend(self.interfaces.iter().flat_map(|i| i.pool.iter().map(|p| &p.selector)));
+        for name in selectors.iter().flat_map(|s| s.referenced_classes()) {
+            if !self.classes.iter().any(|c| c.name == name) {
+                ret.push(format!("Class {} is used but never defined", name));
+            }
+        }
+        for class in &self.classes {
+            if class.is_cyclic(&self.classes) {
+                ret.push(format!("Class {} references itself", class.name));
+            }
+            // The pool decides which subnet it serves, a client of a class can't be on another one
+            if class.options.iter().any(|o| o.get_type() == 1) {
+                ret.push(format!("Class {} sets a SubnetMask, set it on the pool or interface", class.name));
+            }
+        }
+
+        for iface in &self.interfaces {
+            for pool in &iface.pool {
+                for res in &pool.reservations {
+                    if pool.range.contains(res.address) == Some(false) {
+                        ret.push(format!("Reservation {} for {:?} on {} isn't in the range of its pool", res.address, res.host, iface.name));
+                    }
+                    if pool.reservations.iter().filter(|r| r.address == res.address).count() > 1 {
+                        ret.push(format!("Address {} on {} is reserved more than once", res.address, iface.name));
+                    }
+                    if res.options.iter().any(|o| o.get_type() == 1) {
+                        ret.push(format!("Reservation {} on {} sets a SubnetMask, set it on the pool or interface", res.address, iface.name));
+                    }
+                }
+                if let Some(ref name) = pool.name {
+                    if name.is_empty() || name.contains('/') || name.starts_with('.') {
+                        ret.push(format!("Pool name \"{}\" on {} can't be used as directory name", name, iface.name));
+                    }
+                    if iface.pool.iter().filter(|p| p.name.as_ref() == Some(name)).count() > 1 {
+                        ret.push(format!("Pool name {} is used more than once on {}", name, iface.name));
+                    }
+                }
+            }
+        }
+
+        ret.sort();
+        ret.dedup();
+        ret
+    }
+
+    /// Things in the config that work, but probably not as intended
+    pub fn warnings(&self) -> Vec<String> {
+        let mut scopes: Vec<(String, &[DhcpOption])> = vec![(String::from("the global options"), &self.options[..])];
+        scopes.extend(self.classes.iter().map(|c| (format!("class {}", c.name), &c.options[..])));
+        for iface in &self.interfaces {
+            scopes.push((format!("interface {}", iface.name), &iface.options[..]));
+            for pool in &iface.pool {
+                scopes.push((format!("a pool on {}", iface.name), &pool.options[..]));
+                scopes.extend(pool.reservations.iter().map(|r| (format!("the reservation of {}", r.address), &r.options[..])));
+            }
+        }
+
+        // Configs written before option 60 had its own type put the vendor class text into ClientIdentifier.
+        // A real client identifier starts with a type byte (RFC 2132, RFC 4361), which isn't printable.
+        let looks_like_class = |o: &DhcpOption| match *o {
+                DhcpOption::ClientIdentifier(ref id) => !id.is_empty() && id.iter().all(|&b| b >= 0x20 && b < 0x7f),
+                _ => false,
+            };
+        let mut ret: Vec<String> = scopes.iter()
+            .filter(|scope| scope.1.iter().any(|o| looks_like_class(o)))
+            .map(|scope| format!("ClientIdentifier in {} is option 61 now, use VendorClassIdentifier for option 60", scope.0))
+            .collect();
+        ret.dedup();
+        ret
+    }
+}
@@ -0,0 +1,123 @@
+extern crate byteorder;
+
+use self::byteorder::{WriteBytesExt, NetworkEndian, ByteOrder};
+
+#[cfg(test)]
+use quickcheck::Arbitrary;
+#[cfg(test)]
+use quickcheck::Gen;
+
+use std::vec::Vec;
+use std::net::Ipv4Addr;
+use serialize::{Serializeable, HasCode};
+use frame::ethernet::EthernetAddr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArpOperation {
+	Request,
+	Reply
+}
+
+/// ARP for IPv4 over ethernet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Arp {
+	pub operation: ArpOperation,
+	pub sender_hw: EthernetAddr,
+	pub sender_ip: Ipv4Addr,
+	pub target_hw: EthernetAddr,
+	pub target_ip: Ipv4Addr
+}
+
+impl Arp {
+	/// A probe for `addr` (RFC 5227), it doesn't claim any address for us
+	pub fn probe(sender: EthernetAddr, addr: Ipv4Addr) -> Self {
+		Arp {
+			operation: ArpOperation::Request,
+			sender_hw: sender,
+			sender_ip: Ipv4Addr::new(0, 0, 0, 0),
+			target_hw: EthernetAddr([0; 6]),
+			target_ip: addr,
+		}
+	}
+}
+
+impl Serializeable for Arp {
+	fn serialize_onto(&self, buffer: &mut Vec<u8>) {
+		/* Ethernet and IPv4 */
+		buffer.write_u16::<NetworkEndian>(1).unwrap();
+		buffer.write_u16::<NetworkEndian>(0x0800).unwrap();
+		buffer.push(6);
+		buffer.push(4);
+
+		buffer.write_u16::<NetworkEndian>(match self.operation {
+				ArpOperation::Request => 1,
+				ArpOperation::Reply => 2,
+			}).unwrap();
+
+		buffer.extend(self.sender_hw.0.iter());
+		buffer.extend(self.sender_ip.octets().iter());
+		buffer.extend(self.target_hw.0.iter());
+		buffer.extend(self.target_ip.octets().iter());
+	}
+
+	fn deserialize_from(buffer: &[u8]) -> Result<Self, String> {
+		if buffer.len() < 28 {
+			return Err("Buffer is too small. Minimum buffer length to decode arp is 28 bytes.".into());
+		}
+		if NetworkEndian::read_u16(&buffer[0..]) != 1 || NetworkEndian::read_u16(&buffer[2..]) != 0x0800
+				|| buffer[4] != 6 || buffer[5] != 4 {
+			return Err("This ARP packet isn't for IPv4 over ethernet".into());
+		}
+
+		let operation = match NetworkEndian::read_u16(&buffer[6..]) {
+				1 => ArpOperation::Request,
+				2 => ArpOperation::Reply,
+				x => return Err(format!("Unknown ARP operation: {}", x)),
+			};
+
+		let sender_hw = [buffer[8], buffer[9], buffer[10], buffer[11], buffer[12], buffer[13]];
+		let sender_ip = Ipv4Addr::new(buffer[14], buffer[15], buffer[16], buffer[17]);
+		let target_hw = [buffer[18], buffer[19], buffer[20], buffer[21], buffer[22], buffer[23]];
+		let target_ip = Ipv4Addr::new(buffer[24], buffer[25], buffer[26], buffer[27]);
+
+		Ok(Arp {
+			operation: operation,
+			sender_hw: EthernetAddr(sender_hw),
+			sender_ip: sender_ip,
+			target_hw: EthernetAddr(target_hw),
+			target_ip: target_ip,
+		})
+	}
+}
+
+impl HasCode for Arp {
+	type CodeType=u16;
+
+	fn get_code() -> u16 { 0x0806 }
+}
+
+#[cfg(test)]
+impl Arbitrary for Arp {
+	fn arbitrary<G: Gen>(gen: &mut G) -> Self {
+		Arp {
+			operation: if Arbitrary::arbitrary(gen) { ArpOperation::Request } else { ArpOperation::Reply },
+			sender_hw: Arbitrary::arbitrary(gen),
+			sender_ip: Arbitrary::arbitrary(gen),
+			target_hw: Arbitrary::arbitrary(gen),
+			target_ip: Arbitrary::arbitrary(gen),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use frame::arp::Arp;
+	use serialize;
+
+	quickcheck! {
+		fn serialize_arp(arp: Arp) -> bool {
+			let buffer = serialize::serialize(&arp);
+			serialize::deserialize(&buffer) == Ok(arp)
+		}
+	}
+}
@@ -92,6 +92,133 @@ impl ConfigAble for EthernetAddr {
 
 }
 
+/// Matches ethernet addresses, written as `00:1a:2b:*`, `address/mask` or `address/bits`
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct MacPattern {
+    pub addr: EthernetAddr,
+    pub mask: EthernetAddr,
+}
+
+impl MacPattern {
+    pub fn matches(&self, addr: &EthernetAddr) -> bool {
+        (0..6).all(|i| addr.0[i] & self.mask.0[i] == self.addr.0[i] & self.mask.0[i])
+    }
+
+    fn from_wildcard(s: &str) -> Result<Self, String> {
+        let mut parts: Vec<&str> = s.split(':').collect();
+        if parts.len() < 6 && parts.last() == Some(&"*") {
+            parts.resize(6, "*");
+        }
+        if parts.len() != 6 {
+            return Err(format!("\"{}\" doesn't consist of 6 bytes", s));
+        }
+
+        let mut ret = MacPattern { addr: EthernetAddr([0; 6]), mask: EthernetAddr([0; 6]) };
+        for (i, part) in parts.iter().enumerate() {
+            if *part == "*" {
+                continue;
+            }
+            ret.addr.0[i] = u8::from_str_radix(part, 16).map_err(|e| format!("Could not parse \"{}\" as MacPattern: {}", s, e))?;
+            ret.mask.0[i] = 0xff;
+        }
+
+        Ok(ret)
+    }
+
+    fn mask_from_bits(bits: u8) -> Result<EthernetAddr, String> {
+        if bits > 48 {
+            return Err(format!("A mask of {} bits is longer than an EthernetAddr", bits));
+        }
+
+        let mut ret = EthernetAddr([0; 6]);
+        for i in 0..bits as usize {
+            ret.0[i / 8] |= 0x80 >> (i % 8);
+        }
+        Ok(ret)
+    }
+}
+
+impl From<EthernetAddr> for MacPattern {
+    fn from(addr: EthernetAddr) -> Self {
+        MacPattern { addr: addr, mask: EthernetAddr([0xff; 6]) }
+    }
+}
+
+impl FromStr for MacPattern {
+    type Err=String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut split = s.splitn(2, '/');
+        let addr = split.next().unwrap_or("");
+        let mask = match split.next() {
+                None => return Self::from_wildcard(addr),
+                Some(x) => x,
+            };
+
+        let addr = EthernetAddr::from_str(addr)?;
+        let mask = match u8::from_str(mask) {
+                Ok(bits) => Self::mask_from_bits(bits)?,
+                Err(_) => EthernetAddr::from_str(mask)?,
+            };
+
+        Ok(MacPattern { addr: addr, mask: mask })
+    }
+}
+
+impl ConfigAble for MacPattern {
+    fn get_format<F>(_: &mut std::collections::HashSet<String>, fun: &mut F)
+        where F: FnMut(&str) {
+        fun("MacPattern: xx:xx:xx:xx:xx:xx, xx:xx:xx:* or xx:xx:xx:xx:xx:xx/mask");
+    }
+
+    fn parse_from<F>(provider: &mut ConfigProvider, fun: &mut F) -> Result<Self, ParseError>
+        where F: FnMut(String) {
+        // Wildcards aren't valid addresses, everything else starts with one
+        let wildcard: String = match provider.get_next() {
+                Some(txt) => txt.chars().take_while(|c| c.is_digit(16) || *c == ':' || *c == '*').collect(),
+                None => String::new(),
+            };
+        if wildcard.contains('*') {
+            provider.consume(wildcard.len(), fun)?;
+            return MacPattern::from_wildcard(&wildcard).map_err(|e| {
+                    fun(e);
+                    ParseError::Recoverable
+                });
+        }
+
+        let addr = EthernetAddr::parse_from(provider, fun)?;
+        if !provider.get_next().map(|txt| txt.starts_with('/')).unwrap_or(false) {
+            return Ok(MacPattern::from(addr));
+        }
+        provider.consume(1, fun)?;
+
+        // The mask is either an address or the number of leading bits
+        let bits: String = match provider.get_next() {
+                Some(txt) => txt.chars().take_while(|c| c.is_digit(16) || *c == ':').collect(),
+                None => String::new(),
+            };
+        let mask = if bits.contains(':') {
+                EthernetAddr::parse_from(provider, fun)?
+            } else {
+                provider.consume(bits.len(), fun)?;
+                match u8::from_str(&bits).map_err(|e| format!("Could not parse \"{}\" as mask: {}", bits, e)).and_then(MacPattern::mask_from_bits) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        fun(e);
+                        return Err(ParseError::Recoverable);
+                    },
+                }
+            };
+
+        Ok(MacPattern { addr: addr, mask: mask })
+    }
+
+    fn get_name() -> &'static str { "MacPattern" }
+
+    fn get_default() -> Result<Self, ()> { Err(()) }
+
+}
+
 impl<'a> From<&'a MacAddr> for EthernetAddr {
     fn from(arg: &'a MacAddr) -> Self {
         EthernetAddr([arg.0, arg.1, arg.2, arg.3, arg.4, arg.5])
@@ -139,3 +266,26 @@ impl Arbitrary for EthernetAddr {
         EthernetAddr([vals.0, vals.1, vals.2, vals.3, vals.4, vals.5])
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use frame::ethernet::{EthernetAddr, MacPattern};
+    use std::str::FromStr;
+
+    #[test]
+    fn matches_mac_patterns() {
+        let phone = EthernetAddr([0x00, 0x1a, 0x2b, 0x10, 0x20, 0x30]);
+        let other = EthernetAddr([0x00, 0x1a, 0x2c, 0x10, 0x20, 0x30]);
+
+        for pattern in &["00:1a:2b:*", "00:1a:2b:00:00:00/24", "00:1a:2b:00:00:00/ff:ff:ff:00:00:00", "00:1a:2b:10:20:30"] {
+            let pattern = MacPattern::from_str(pattern).unwrap();
+            assert!(pattern.matches(&phone));
+            assert!(!pattern.matches(&other));
+        }
+
+        assert!(!MacPattern::from_str("00:*:2b:10:20:30").unwrap().matches(&other));
+        assert!(MacPattern::from_str("00:1a:*:10:20:30").unwrap().matches(&other));
+        assert!(MacPattern::from_str("00:1a:2b").is_err());
+        assert!(MacPattern::from_str("00:1a:2b:00:00:00/49").is_err());
+    }
+}
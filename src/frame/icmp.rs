@@ -0,0 +1,110 @@
+extern crate byteorder;
+extern crate pnet;
+
+use self::byteorder::{WriteBytesExt, NetworkEndian, ByteOrder};
+use self::pnet::util::checksum;
+
+#[cfg(test)]
+use quickcheck::Arbitrary;
+#[cfg(test)]
+use quickcheck::Gen;
+
+use std::vec::Vec;
+use serialize::{Serializeable, HasCode};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EchoType {
+	Request,
+	Reply
+}
+
+impl EchoType {
+	fn get_value(&self) -> u8 {
+		match *self {
+			EchoType::Request => 8,
+			EchoType::Reply => 0,
+		}
+	}
+}
+
+/// ICMP echo request/reply, the only ICMP messages we care about
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IcmpEcho {
+	pub echo_type: EchoType,
+	pub ident: u16,
+	pub seq: u16,
+	pub payload: Box<[u8]>
+}
+
+impl Serializeable for IcmpEcho {
+	fn serialize_onto(&self, buffer: &mut Vec<u8>) {
+		let start = buffer.len();
+		buffer.push(self.echo_type.get_value());
+		buffer.push(0);
+
+		/* First set checksum to 0 */
+		buffer.write_u16::<NetworkEndian>(0).unwrap();
+		buffer.write_u16::<NetworkEndian>(self.ident).unwrap();
+		buffer.write_u16::<NetworkEndian>(self.seq).unwrap();
+		buffer.extend(self.payload.iter());
+
+		let checksum = checksum(&buffer.as_slice()[start..], 1);
+
+		buffer[start + 2] = (checksum >> 8) as u8;
+		buffer[start + 3] = (checksum & 0xFF) as u8;
+	}
+
+	fn deserialize_from(buffer: &[u8]) -> Result<Self, String> {
+		if buffer.len() < 8 {
+			return Err("Buffer is too small. Minimum buffer length to decode icmp echo is 8 bytes.".into());
+		}
+
+		let echo_type = match buffer[0] {
+				8 => EchoType::Request,
+				0 => EchoType::Reply,
+				_ => return Err("This ICMP message isn't an echo request or reply".into()),
+			};
+
+		let checksum = checksum(buffer, 1);
+		if buffer[2] != (checksum >> 8) as u8 || buffer[3] != (checksum & 0xFF) as u8 {
+			return Err("ICMP checksum validation failed".into());
+		}
+
+		let ident = NetworkEndian::read_u16(&buffer[4..]);
+		let seq = NetworkEndian::read_u16(&buffer[6..]);
+
+		Ok(IcmpEcho{echo_type: echo_type, ident: ident, seq: seq, payload: buffer[8..].to_vec().into_boxed_slice()})
+	}
+}
+
+impl HasCode for IcmpEcho {
+	type CodeType=u8;
+
+	fn get_code() -> u8 { 1 }
+}
+
+#[cfg(test)]
+impl Arbitrary for IcmpEcho {
+	fn arbitrary<G: Gen>(gen: &mut G) -> Self {
+		let payload: Vec<u8> = Arbitrary::arbitrary(gen);
+		IcmpEcho {
+			echo_type: if Arbitrary::arbitrary(gen) { EchoType::Request } else { EchoType::Reply },
+			ident: Arbitrary::arbitrary(gen),
+			seq: Arbitrary::arbitrary(gen),
+			payload: payload.into_boxed_slice(),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use frame::icmp::IcmpEcho;
+	use serialize;
+
+	quickcheck! {
+		fn serialize_echo(echo: IcmpEcho) -> bool {
+			let buffer = serialize::serialize(&echo);
+			serialize::deserialize(&buffer) == Ok(echo)
+		}
+	}
+}
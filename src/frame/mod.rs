@@ -1,3 +1,5 @@
+pub mod arp;
 pub mod ethernet;
+pub mod icmp;
 pub mod ip4;
 pub mod udp;
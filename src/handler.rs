@@ -6,8 +6,13 @@ use config;
 use std::marker::PhantomData;
 use interface::Interface;
 use std::ops::Deref;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, Sender, TryRecvError};
+use std::time::{Duration, Instant};
 
+use frame::arp::Arp;
 use frame::ethernet::{Ethernet, EthernetAddr};
+use frame::icmp::{EchoType, IcmpEcho};
 use frame::ip4::{IPv4Packet};
 use frame::udp::UDP;
 use std::net::Ipv4Addr;
@@ -16,6 +21,33 @@ use packet;
 use lease;
 use allocationunit;
 
+/// Largest IP datagram we can put on the wire
+const ETHERNET_MTU: u16 = 1500;
+
+/// Identifies the echo requests of an offer, so late replies to an earlier one don't match
+static NEXT_IDENT: AtomicUsize = AtomicUsize::new(0);
+
+type DhcpFrame = Ethernet<IPv4Packet<UDP<packet::DhcpPacket<EthernetAddr>, packet::DhcpServer>>>;
+
+/// Messages from the main thread to the interface threads
+pub enum Control {
+    /// Apply a new configuration for the interface, with the global options and classes
+    Reload(config::Interface, Vec<packet::DhcpOption>, Vec<config::Class>),
+    /// Save the state and stop handling the interface
+    Stop,
+}
+
+/// An offer waiting for the check that its address is unused
+struct PendingOffer {
+    request: DhcpFrame,
+    answer: packet::DhcpPacket<EthernetAddr>,
+    s_ip: Ipv4Addr,
+    addr: Ipv4Addr,
+    /// The ident of our echo request, `None` for an ARP probe
+    ident: Option<u16>,
+    deadline: Instant,
+}
+
 fn get_server_ip<'a, I>(arg: I, client: Ipv4Addr, mask: Ipv4Addr) -> Option<&'a Ipv4Addr>
     where I: IntoIterator<Item=&'a Ipv4Addr> {
     let cmp_mask: u32 = mask.into();
@@ -32,70 +64,106 @@ fn get_server_ip<'a, I>(arg: I, client: Ipv4Addr, mask: Ipv4Addr) -> Option<&'a
     None
 }
 
+/// The address the request was sent to if it's one of ours, otherwise our first address
+fn get_local_ip(my_ip: &[Ipv4Addr], dst: Ipv4Addr) -> Option<Ipv4Addr> {
+    my_ip.iter().find(|ip| **ip == dst).or_else(|| my_ip.first()).cloned()
+}
+
+/// Get the address we use as server identifier for a client that gets `addr`.
+/// Relayed clients aren't on one of our subnets, so we use the address the relay talked to.
+fn get_reply_ip(iface: &Interface,
+                request: &packet::DhcpPacket<EthernetAddr>,
+                dst: Ipv4Addr,
+                addr: Ipv4Addr,
+                mask: Ipv4Addr) -> Option<Ipv4Addr> {
+    match request.gateway_addr {
+        Some(_) => get_local_ip(&iface.my_ip, dst),
+        None => get_server_ip(&iface.my_ip, addr, mask).cloned(),
+    }
+}
+
+/// Relay agents expect their information to be echoed back (RFC 3046)
+fn push_relay_info(opts: &mut Vec<packet::DhcpOption>, request: &packet::DhcpPacket<EthernetAddr>) {
+    if let Some(subs) = request.get_relay_info() {
+        opts.push(packet::DhcpOption::RelayAgentInformation(subs.to_vec().into_boxed_slice()));
+    }
+}
+
+/// Find the allocation unit for a client.
+/// Relayed requests are served from the pool on the relays subnet, everything else from pools on
+/// one of our local subnets.
 fn alloc_for_client<'a>(aus: &'a mut [allocationunit::AllocationUnit],
-                        client: &lease::Client<::frame::ethernet::EthernetAddr>)
+                        client: &lease::Client<::frame::ethernet::EthernetAddr>,
+                        request: &packet::DhcpPacket<EthernetAddr>,
+                        local: &[Ipv4Addr])
                         -> Option<&'a mut allocationunit::AllocationUnit> {
-    aus.iter_mut().find(|alloc| alloc.is_suitable(client))
+    aus.iter_mut().find(|alloc| alloc.is_suitable(client, request)
+                        && match request.get_link() {
+                            Some(relay) => alloc.serves(relay),
+                            None => local.iter().any(|ip| alloc.serves(*ip)),
+                        })
 }
 
-fn decode_dhcp(rec: &[u8]) -> Result<IPv4Packet<UDP<packet::DhcpPacket<EthernetAddr>, packet::DhcpServer>>, String> {
-    let tmp = serialize::deserialize::<Ethernet<IPv4Packet<UDP<packet::DhcpPacket<EthernetAddr>, packet::DhcpServer>>>>(rec)?;
-    Ok(tmp.payload)
+fn decode_dhcp(rec: &[u8]) -> Result<DhcpFrame, String> {
+    serialize::deserialize::<DhcpFrame>(rec)
 }
 
-fn get_ack(iface: &mut Interface, request: &packet::DhcpPacket<EthernetAddr>) -> Option<(packet::DhcpPacket<EthernetAddr>, Ipv4Addr)> {
-    let client = lease::get_client(request);
+fn decode_echo(rec: &[u8]) -> Result<Ethernet<IPv4Packet<IcmpEcho>>, String> {
+    serialize::deserialize::<Ethernet<IPv4Packet<IcmpEcho>>>(rec)
+}
+
+fn decode_arp(rec: &[u8]) -> Result<Ethernet<Arp>, String> {
+    serialize::deserialize::<Ethernet<Arp>>(rec)
+}
+
+/// The client states a DHCPREQUEST can be sent from (RFC 2131 section 4.3.2)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RequestState {
+    /// Answering an offer of the server (first) for the requested address (second)
+    Selecting(Ipv4Addr, Ipv4Addr),
+    /// Verifying a previously leased address after a reboot
+    InitReboot(Ipv4Addr),
+    /// Extending the lease with the server that granted it
+    Renewing(Ipv4Addr),
+    /// Extending the lease with any server
+    Rebinding(Ipv4Addr),
+}
+
+/// The server the client addresses with `packet` (option 54)
+fn get_server_id(packet: &packet::DhcpPacket<EthernetAddr>) -> Option<Ipv4Addr> {
+    packet.options.iter().filter_map(|opt|
+        match *opt {
+            packet::DhcpOption::ServerIdentifier(ip) => Some(ip),
+            _ => None
+        }).next()
+}
+
+fn get_request_state(request: &packet::DhcpPacket<EthernetAddr>, dst: Ipv4Addr) -> Option<RequestState> {
+    let server = get_server_id(request);
     let req_addr = request.options.iter().filter_map(|opt|
         match *opt {
             packet::DhcpOption::AddressRequest(ip) => Some(ip),
             _ => None
         }).next();
-    if let Some(au) = alloc_for_client(&mut iface.allocators, &client) {
-        let mask = *au.get_mask();
-        let mut opts: Vec<packet::DhcpOption> = au.get_options().iter().map(|x| (*x).clone()).collect();
-        if let Some(l) = au.get_renewed_lease(&client, req_addr) {
-            let addr = l.assigned;
-            let s_ip = match get_server_ip(&iface.my_ip, addr, mask) {
-                    Some(i) => i,
-                    None => {
-                        error!("Tried to assign an IP I can't find a suitable server address for!");
-                        return None;
-                    },
-                };
 
-            opts.push(packet::DhcpOption::ServerIdentifier(*s_ip));
-            let answer = packet::DhcpPacket {
-                packet_type: packet::PacketType::Ack,
-                xid: request.xid,
-                seconds: 0,
-                client_addr: None,
-                your_addr: Some(addr),
-                server_addr: None,
-                gateway_addr: None,
-                client_hwaddr: request.client_hwaddr,
-                options: opts,
-                flags: Vec::new(),
-                };
-            debug!("Replying to request: {:?}", &answer);
-            return Some((answer, *s_ip));
-        }
-
-        let answer = packet::DhcpPacket {
-            packet_type: packet::PacketType::Nack,
-            xid: request.xid,
-            seconds: 0,
-            client_addr: None,
-            your_addr: None,
-            server_addr: None,
-            gateway_addr: None,
-            client_hwaddr: request.client_hwaddr,
-            options: vec![packet::DhcpOption::Message("Can't give you this address. Did I offer it?".into())],
-            flags: Vec::new(),
-            };
-        let s_ip = iface.my_ip[0];
-
-        return Some((answer, s_ip))
+    match (server, request.client_addr, req_addr) {
+        (Some(server), _, Some(addr)) => Some(RequestState::Selecting(server, addr)),
+        (None, None, Some(addr)) => Some(RequestState::InitReboot(addr)),
+        (None, Some(addr), _) => {
+            // Renewing clients unicast to us, rebinding ones broadcast (maybe through a relay)
+            if dst == Ipv4Addr::new(255, 255, 255, 255) || request.gateway_addr.is_some() {
+                Some(RequestState::Rebinding(addr))
+            } else {
+                Some(RequestState::Renewing(addr))
+            }
+        },
+        _ => None,
     }
+}
+
+fn get_nack(iface: &Interface, request: &packet::DhcpPacket<EthernetAddr>, dst: Ipv4Addr, msg: &str) -> Option<(packet::DhcpPacket<EthernetAddr>, Ipv4Addr)> {
+    let mut opts = vec![packet::DhcpOption::Message(msg.into())];
+    push_relay_info(&mut opts, request);
     let answer = packet::DhcpPacket {
         packet_type: packet::PacketType::Nack,
         xid: request.xid,
@@ -103,12 +171,137 @@ fn get_ack(iface: &mut Interface, request: &packet::DhcpPacket<EthernetAddr>) ->
         client_addr: None,
         your_addr: None,
         server_addr: None,
-        gateway_addr: None,
+        gateway_addr: request.gateway_addr,
         client_hwaddr: request.client_hwaddr,
-        options: vec![packet::DhcpOption::Message("Can't find a viable allocator for this client".into())],
+        options: opts,
         flags: Vec::new(),
+        max_size: None,
         };
-    let s_ip = iface.my_ip[0];
+    debug!("Denying request: {:?}", &answer);
+
+    get_local_ip(&iface.my_ip, dst).map(|s_ip| (answer, s_ip))
+}
+
+/// Decide whether to acknowledge the address a request is for.
+/// `None` means we have to stay silent, because another server may be responsible for the client.
+fn check_request(au: &allocationunit::AllocationUnit,
+                 client: &lease::Client<EthernetAddr>,
+                 state: RequestState)
+                 -> Option<Result<Ipv4Addr, &'static str>> {
+    match state {
+        RequestState::Selecting(_, addr) => {
+            if au.has_allocation(client, addr) {
+                Some(Ok(addr))
+            } else {
+                Some(Err("Can't give you this address. Did I offer it?"))
+            }
+        },
+        RequestState::InitReboot(addr) | RequestState::Renewing(addr) | RequestState::Rebinding(addr) if !au.serves(addr) => {
+            Some(Err("The requested address isn't on this network"))
+        },
+        RequestState::InitReboot(addr) => {
+            match au.get_allocated(client) {
+                None => None,
+                Some(known) if known == addr => Some(Ok(addr)),
+                Some(_) => Some(Err("This isn't your address")),
+            }
+        },
+        RequestState::Renewing(addr) => {
+            if au.has_allocation(client, addr) {
+                Some(Ok(addr))
+            } else {
+                Some(Err("I don't know about your lease"))
+            }
+        },
+        RequestState::Rebinding(addr) => {
+            if au.has_allocation(client, addr) {
+                Some(Ok(addr))
+            } else {
+                None
+            }
+        },
+    }
+}
+
+fn get_ack(iface: &mut Interface, request: &packet::DhcpPacket<EthernetAddr>, dst: Ipv4Addr) -> Option<(packet::DhcpPacket<EthernetAddr>, Ipv4Addr)> {
+    let client = lease::get_client(request);
+    let state = match get_request_state(request, dst) {
+            Some(x) => x,
+            None => {
+                warn!("Couldn't figure out what {} is requesting", client.hw_addr);
+                return None;
+            },
+        };
+    debug!("Request from {} in state {:?}", client.hw_addr, state);
+
+    if let RequestState::Selecting(server, _) = state {
+        if !iface.my_ip.contains(&server) {
+            info!("{} selected the offer from {}", client.hw_addr, server);
+            // RFC 2131 4.3.2, our offer wasn't taken and its address can go to someone else
+            if let Some(au) = alloc_for_client(&mut iface.allocators, &client, request, &iface.my_ip) {
+                au.drop_offer(&client);
+            }
+            return None;
+        }
+    }
+
+    let result = {
+        let au = match alloc_for_client(&mut iface.allocators, &client, request, &iface.my_ip) {
+                Some(x) => x,
+                None => {
+                    if let RequestState::Selecting(_, _) = state {
+                        return get_nack(iface, request, dst, "Can't find a viable allocator for this client");
+                    }
+                    return None;
+                },
+            };
+
+        match check_request(au, &client, state) {
+            None => {
+                debug!("Staying silent, {} may be handled by another server", client.hw_addr);
+                return None;
+            },
+            Some(Err(msg)) => Err(msg),
+            Some(Ok(req_addr)) => {
+                let mask = *au.get_mask();
+                let opts = au.get_options_for(&client, request);
+                match au.get_renewed_lease(&client, request, Some(req_addr)) {
+                    Some(l) => Ok((opts, l.assigned, mask)),
+                    None => Err("Can't give you this address. Did I offer it?"),
+                }
+            },
+        }
+    };
+
+    let (mut opts, addr, mask) = match result {
+            Ok(x) => x,
+            Err(msg) => return get_nack(iface, request, dst, msg),
+        };
+
+    let s_ip = match get_reply_ip(iface, request, dst, addr, mask) {
+            Some(i) => i,
+            None => {
+                error!("Tried to assign an IP I can't find a suitable server address for!");
+                return None;
+            },
+        };
+
+    opts.push(packet::DhcpOption::ServerIdentifier(s_ip));
+    push_relay_info(&mut opts, request);
+    let answer = packet::DhcpPacket {
+        packet_type: packet::PacketType::Ack,
+        xid: request.xid,
+        seconds: 0,
+        client_addr: request.client_addr,
+        your_addr: Some(addr),
+        server_addr: None,
+        gateway_addr: request.gateway_addr,
+        client_hwaddr: request.client_hwaddr,
+        options: opts,
+        flags: Vec::new(),
+        max_size: None,
+        };
+    debug!("Replying to request: {:?}", &answer);
 
     Some((answer, s_ip))
 }
@@ -122,26 +315,27 @@ fn get_offer_alloc<'a>(au: &'a mut allocationunit::AllocationUnit,
     au.get_allocation(client, None)
 }
 
-fn get_offer(iface: &mut Interface, discover: &packet::DhcpPacket<EthernetAddr>) -> Option<(packet::DhcpPacket<EthernetAddr>, Ipv4Addr)> {
+fn get_offer(iface: &mut Interface, discover: &packet::DhcpPacket<EthernetAddr>, dst: Ipv4Addr) -> Option<(packet::DhcpPacket<EthernetAddr>, Ipv4Addr)> {
     let client = lease::get_client(discover);
     let req_addr = discover.options.iter().flat_map(|opt|
         match *opt {
             packet::DhcpOption::AddressRequest(ip) => Some(ip),
             _ => None
         }).next();
-    if let Some(mut au) = alloc_for_client(&mut iface.allocators, &client) {
+    if let Some(mut au) = alloc_for_client(&mut iface.allocators, &client, discover, &iface.my_ip) {
         let mask = *au.get_mask();
-        let mut opts: Vec<packet::DhcpOption> = au.get_options().iter().map(|x| (*x).clone()).collect();
+        let mut opts = au.get_options_for(&client, discover);
         if let Some(alloc) = get_offer_alloc(&mut au, &client, req_addr) {
             let addr = alloc.assigned;
-            let s_ip = match get_server_ip(&iface.my_ip, addr, mask) {
+            let s_ip = match get_reply_ip(iface, discover, dst, addr, mask) {
                     Some(i) => i,
                     None => {
                         error!("Tried to assign an IP I can't find a suitable server address for!");
                         return None;
                     },
                 };
-            opts.push(packet::DhcpOption::ServerIdentifier(*s_ip));
+            opts.push(packet::DhcpOption::ServerIdentifier(s_ip));
+            push_relay_info(&mut opts, discover);
             let offer = packet::DhcpPacket {
                 packet_type: packet::PacketType::Offer,
                 xid: discover.xid,
@@ -149,72 +343,135 @@ fn get_offer(iface: &mut Interface, discover: &packet::DhcpPacket<EthernetAddr>)
                 client_addr: None,
                 your_addr: Some(addr),
                 server_addr: None,
-                gateway_addr: None,
+                gateway_addr: discover.gateway_addr,
                 client_hwaddr: discover.client_hwaddr,
                 options: opts,
                 flags: Vec::new(),
+                max_size: None,
                 };
             debug!("Making offer: {:?}", &offer);
-            return Some((offer, *s_ip));
+            return Some((offer, s_ip));
         }
     }
 
     None
 }
 
-//TODO: Check what exactly we need in here
-fn get_inform(iface: &mut Interface, discover: &packet::DhcpPacket<EthernetAddr>) -> Option<(packet::DhcpPacket<EthernetAddr>, Ipv4Addr)> {
-    let client = lease::get_client(discover);
-    if let Some(au) = alloc_for_client(&mut iface.allocators, &client) {
-        let opts: Vec<packet::DhcpOption> = au.get_options().iter().map(|x| (*x).clone()).collect();
-        let offer = packet::DhcpPacket {
-            packet_type: packet::PacketType::Offer,
-            xid: discover.xid,
-            seconds: 0,
-            client_addr: None,
-            your_addr: None,
-            server_addr: None,
-            gateway_addr: None,
-            client_hwaddr: discover.client_hwaddr,
-            options: opts,
-            flags: Vec::new(),
-            };
-        debug!("Informing: {:?}", &offer);
-        let s_ip = iface.my_ip[0];
-        return Some((offer, s_ip));
-    }
+/// Answer a client that configured its address itself and only wants the options (RFC 2131 section 4.3.5)
+fn get_inform(iface: &mut Interface, inform: &packet::DhcpPacket<EthernetAddr>, dst: Ipv4Addr) -> Option<(packet::DhcpPacket<EthernetAddr>, Ipv4Addr)> {
+    let client = lease::get_client(inform);
+    let addr = match inform.client_addr {
+            Some(x) => x,
+            None => {
+                warn!("Got an inform without client address from {}", client.hw_addr);
+                return None;
+            },
+        };
 
-    None
+    let (mut opts, mask) = match alloc_for_client(&mut iface.allocators, &client, inform, &iface.my_ip) {
+            Some(au) => {
+                // There's no lease involved, so don't tell the client anything about one
+                let opts: Vec<packet::DhcpOption> = au.get_options_for(&client, inform).into_iter()
+                    .filter(|x| x.get_type() != 51 && x.get_type() != 58 && x.get_type() != 59)
+                    .collect();
+                (opts, *au.get_mask())
+            },
+            None => return None,
+        };
+
+    let s_ip = match get_reply_ip(iface, inform, dst, addr, mask) {
+            Some(i) => i,
+            None => {
+                error!("Got an inform from {} I can't find a suitable server address for!", addr);
+                return None;
+            },
+        };
+
+    opts.push(packet::DhcpOption::ServerIdentifier(s_ip));
+    push_relay_info(&mut opts, inform);
+    let answer = packet::DhcpPacket {
+        packet_type: packet::PacketType::Ack,
+        xid: inform.xid,
+        seconds: 0,
+        client_addr: inform.client_addr,
+        your_addr: None,
+        server_addr: None,
+        gateway_addr: inform.gateway_addr,
+        client_hwaddr: inform.client_hwaddr,
+        options: opts,
+        flags: Vec::new(),
+        max_size: None,
+        };
+    debug!("Informing: {:?}", &answer);
+
+    Some((answer, s_ip))
 }
 
 fn release(iface: &mut Interface, packet: &packet::DhcpPacket<EthernetAddr>) {
     let client = lease::get_client(packet);
-    if let Some(au) = alloc_for_client(&mut iface.allocators, &client) {
+    if let Some(au) = alloc_for_client(&mut iface.allocators, &client, packet, &iface.my_ip) {
         if let Some(addr) = packet.client_addr {
             au.free_lease(&client, addr);
         }
     }
 }
 
-fn get_answer(iface: &mut Interface, packet: &packet::DhcpPacket<EthernetAddr>) -> Option<(packet::DhcpPacket<EthernetAddr>, Ipv4Addr)> {
+fn decline(iface: &mut Interface, packet: &packet::DhcpPacket<EthernetAddr>) {
+    let client = lease::get_client(packet);
+    // Another server on the same link made the offer, the address isn't ours to quarantine
+    if let Some(server) = get_server_id(packet) {
+        if !iface.my_ip.contains(&server) {
+            debug!("{} declined an address of {}", client.hw_addr, server);
+            return;
+        }
+    }
+    let addr = match packet.options.iter().filter_map(|opt|
+        match *opt {
+            packet::DhcpOption::AddressRequest(ip) => Some(ip),
+            _ => None
+        }).next() {
+            Some(x) => x,
+            None => {
+                warn!("Got a decline without requested address from {}", client.hw_addr);
+                return;
+            },
+        };
+    let msg = packet.options.iter().filter_map(|opt|
+        match *opt {
+            packet::DhcpOption::Message(ref m) => Some(m.as_str()),
+            _ => None
+        }).next().unwrap_or("");
+
+    warn!("{} ({:?}) declined {}: {}", client.hw_addr, client.hostname, addr, msg);
+    if let Some(au) = alloc_for_client(&mut iface.allocators, &client, packet, &iface.my_ip) {
+        au.decline(&client, addr);
+    }
+}
+
+fn get_answer(iface: &mut Interface, packet: &packet::DhcpPacket<EthernetAddr>, dst: Ipv4Addr) -> Option<(packet::DhcpPacket<EthernetAddr>, Ipv4Addr)> {
     match packet.packet_type {
         packet::PacketType::Discover => {
             trace!("Creating an offer");
-            get_offer(iface, packet)
+            get_offer(iface, packet, dst)
         },
         packet::PacketType::Request => {
             trace!("Handling a request");
-            get_ack(iface, packet)
+            get_ack(iface, packet, dst)
         },
         packet::PacketType::Inform => {
             trace!("Someone wants to get informed");
-            get_inform(iface, packet)
+            get_inform(iface, packet, dst)
         }
         packet::PacketType::Release => {
             trace!("Someone wants to get informed");
             release(iface, packet);
             None
         }
+        packet::PacketType::Decline => {
+            trace!("Someone declined an address");
+            decline(iface, packet);
+            None
+        }
         x => {
             warn!("Found unhandled dhcp packet type: {:?}", x);
             None
@@ -223,60 +480,403 @@ fn get_answer(iface: &mut Interface, packet: &packet::DhcpPacket<EthernetAddr>)
 }
 
 
+/// Pick the ethernet address, ip address and port the answer is sent to (RFC 2131 section 4.1).
+/// `sender` is the ethernet address the request came from, for relayed requests that's the relay.
+fn get_destination(request: &packet::DhcpPacket<EthernetAddr>,
+                   answer: &packet::DhcpPacket<EthernetAddr>,
+                   sender: EthernetAddr)
+                   -> (EthernetAddr, Ipv4Addr, u16) {
+    let broadcast = (EthernetAddr([0xff; 6]), Ipv4Addr::new(255, 255, 255, 255), 68);
+
+    if let Some(relay) = request.gateway_addr {
+        return (sender, relay, 67);
+    }
+
+    if answer.packet_type == packet::PacketType::Nack {
+        return broadcast;
+    }
+
+    if let Some(addr) = request.client_addr {
+        return (request.client_hwaddr, addr, 68);
+    }
+
+    if request.flags.contains(&packet::DhcpFlags::Broadcast) {
+        return broadcast;
+    }
+
+    match answer.your_addr {
+        Some(addr) => (request.client_hwaddr, addr, 68),
+        None => broadcast,
+    }
+}
+
+/// Answers carry the broadcast flag of the request, relays need it to deliver them.
+/// A relayed NAK always has to be broadcast by the relay.
+fn set_reply_flags(answer: &mut packet::DhcpPacket<EthernetAddr>, request: &packet::DhcpPacket<EthernetAddr>) {
+    answer.flags = request.flags.clone();
+
+    if request.gateway_addr.is_some()
+            && answer.packet_type == packet::PacketType::Nack
+            && !answer.flags.contains(&packet::DhcpFlags::Broadcast) {
+        answer.flags.push(packet::DhcpFlags::Broadcast);
+    }
+}
+
+fn send_answer(tx: &mut std::boxed::Box<pnet::datalink::DataLinkSender>,
+               iface: &Interface,
+               frame: &DhcpFrame,
+               mut answer: packet::DhcpPacket<EthernetAddr>,
+               s_ip: Ipv4Addr) {
+    let request = &frame.payload.payload.payload;
+    set_reply_flags(&mut answer, request);
+    // Anything larger than the client accepts or the link carries gets overloaded
+    answer.max_size = Some(std::cmp::min(request.get_max_message_size(), ETHERNET_MTU));
+    let (target_mac, target_ip, port) = get_destination(request, &answer, frame.src);
+
+    let udp: UDP<packet::DhcpPacket<EthernetAddr>, packet::DhcpServer>  = UDP {remote: port, payload: answer, local: PhantomData};
+    let ip = IPv4Packet { src: s_ip, dst: target_ip, ttl: 64, payload: udp};
+    let ethernet = Ethernet{src: EthernetAddr::from(&iface.my_mac), dst: target_mac, payload: ip};
+
+    let tmp = serialize::serialize(&ethernet);
+
+    tx.send_to(tmp.deref(), None);
+}
+
+/// Ping `addr` to find out whether someone already uses it.
+/// `dst` is the relay the request came through, on the local link we don't know the MAC for `addr` and broadcast it.
+fn send_ping(tx: &mut std::boxed::Box<pnet::datalink::DataLinkSender>,
+             iface: &Interface,
+             dst: EthernetAddr,
+             s_ip: Ipv4Addr,
+             addr: Ipv4Addr,
+             ident: u16) {
+    let echo = IcmpEcho { echo_type: EchoType::Request, ident: ident, seq: 0, payload: Box::new([]) };
+    let ip = IPv4Packet { src: s_ip, dst: addr, ttl: 64, payload: echo };
+    let ethernet = Ethernet{src: EthernetAddr::from(&iface.my_mac), dst: dst, payload: ip};
+
+    let tmp = serialize::serialize(&ethernet);
+
+    tx.send_to(tmp.deref(), None);
+}
+
+fn send_arp_probe(tx: &mut std::boxed::Box<pnet::datalink::DataLinkSender>,
+                  iface: &Interface,
+                  addr: Ipv4Addr) {
+    let my_mac = EthernetAddr::from(&iface.my_mac);
+    let ethernet = Ethernet{src: my_mac, dst: EthernetAddr([0xff; 6]), payload: Arp::probe(my_mac, addr)};
+
+    let tmp = serialize::serialize(&ethernet);
+
+    tx.send_to(tmp.deref(), None);
+}
+
+/// The probe configured for the pool `addr` is offered from
+fn get_probe(iface: &Interface, request: &packet::DhcpPacket<EthernetAddr>, addr: Ipv4Addr) -> config::Probe {
+    let client = lease::get_client(request);
+    iface.allocators.iter()
+        .find(|au| au.has_allocation(&client, addr))
+        .map(|au| au.get_probe(&client, addr))
+        .unwrap_or(config::Probe::Off)
+}
+
 fn handle_packet(
         tx: &mut std::boxed::Box<pnet::datalink::DataLinkSender>,
         iface: &mut Interface,
-        packet: IPv4Packet<UDP<packet::DhcpPacket<EthernetAddr>, packet::DhcpServer>>) {
-    let target_mac = packet.payload.payload.client_hwaddr;
-    if let Some((answer, s_ip)) = get_answer(iface, &packet.payload.payload) {
-        let target_ip = if packet.src == Ipv4Addr::new(0, 0, 0, 0) {
-                Ipv4Addr::new(255, 255, 255, 255)
-            } else {
-                packet.src
-            };
+        frame: DhcpFrame,
+        pending: &mut Vec<PendingOffer>) {
+    // The client would answer the probe for an address it already had itself
+    let client = lease::get_client(&frame.payload.payload.payload);
+    let previous: Vec<Ipv4Addr> = iface.allocators.iter().filter_map(|au| au.get_allocated(&client)).collect();
 
+    let answer = get_answer(iface, &frame.payload.payload.payload, frame.payload.dst);
+    let (answer, s_ip) = match answer {
+            Some(x) => x,
+            None => return,
+        };
 
-        let udp: UDP<packet::DhcpPacket<EthernetAddr>, packet::DhcpServer>  = UDP {remote: 68, payload: answer, local: PhantomData};
-        let ip = IPv4Packet { src: s_ip, dst: target_ip, ttl: 64, payload: udp};
-        let ethernet = Ethernet{src: EthernetAddr::from(&iface.my_mac), dst: target_mac, payload: ip};
+    let addr = match answer.your_addr {
+            Some(addr) if answer.packet_type == packet::PacketType::Offer => addr,
+            _ => return send_answer(tx, iface, &frame, answer, s_ip),
+        };
 
-        let tmp = serialize::serialize(&ethernet);
+    // The client retransmitted while we are still waiting
+    if pending.iter().any(|p| p.answer.xid == answer.xid && p.answer.client_hwaddr == answer.client_hwaddr) {
+        return;
+    }
 
-        tx.send_to(tmp.deref(), None);
+    let relayed = frame.payload.payload.payload.gateway_addr.is_some();
+    let probe = if previous.contains(&addr) {
+            config::Probe::Off
+        } else {
+            get_probe(iface, &frame.payload.payload.payload, addr)
+        };
+    match probe {
+        config::Probe::Off => send_answer(tx, iface, &frame, answer, s_ip),
+        config::Probe::Arp(timeout) if !relayed => {
+            debug!("Sending ARP probe for {} before offering it", addr);
+            send_arp_probe(tx, iface, addr);
+            pending.push(PendingOffer {
+                request: frame,
+                answer: answer,
+                s_ip: s_ip,
+                addr: addr,
+                ident: None,
+                deadline: Instant::now() + Duration::from_millis(u64::from(timeout)),
+                });
+        },
+        config::Probe::Ping(timeout) | config::Probe::Arp(timeout) => {
+            debug!("Pinging {} before offering it", addr);
+            let ident = NEXT_IDENT.fetch_add(1, Ordering::Relaxed) as u16;
+            let dst = if relayed { frame.src } else { EthernetAddr([0xff; 6]) };
+            send_ping(tx, iface, dst, s_ip, addr, ident);
+            pending.push(PendingOffer {
+                request: frame,
+                answer: answer,
+                s_ip: s_ip,
+                addr: addr,
+                ident: Some(ident),
+                deadline: Instant::now() + Duration::from_millis(u64::from(timeout)),
+                });
+        },
     }
 }
 
+/// An echo reply for an address we want to offer means it's already in use
+fn handle_echo(tx: &mut std::boxed::Box<pnet::datalink::DataLinkSender>,
+               iface: &mut Interface,
+               frame: Ethernet<IPv4Packet<IcmpEcho>>,
+               pending: &mut Vec<PendingOffer>) {
+    let packet = frame.payload;
+    if packet.payload.echo_type != EchoType::Reply {
+        return;
+    }
+
+    if let Some(index) = pending.iter().position(|p| p.addr == packet.src && p.ident == Some(packet.payload.ident)) {
+        warn!("{} answered our ping, it's in use already", packet.src);
+        conflict(tx, iface, index, pending);
+    }
+}
+
+/// Any ARP packet from the address we want to offer means it's already in use.
+/// This also catches hosts that are probing for the address themselves.
+/// The client we make the offer to may still hold on to the address, that's no conflict.
+fn handle_arp(tx: &mut std::boxed::Box<pnet::datalink::DataLinkSender>,
+              iface: &mut Interface,
+              frame: Ethernet<Arp>,
+              pending: &mut Vec<PendingOffer>) {
+    let arp = frame.payload;
+    if arp.sender_hw == EthernetAddr::from(&iface.my_mac) {
+        return;
+    }
+
+    let index = pending.iter().position(|p| p.answer.client_hwaddr != arp.sender_hw
+                                        && (p.addr == arp.sender_ip
+                                            || (p.addr == arp.target_ip && arp.sender_ip == Ipv4Addr::new(0, 0, 0, 0))));
+    if let Some(index) = index {
+        warn!("{} uses {} already", arp.sender_hw, pending[index].addr);
+        conflict(tx, iface, index, pending);
+    }
+}
+
+/// The address of the pending offer at `index` is in use, keep it out of the pool and make a new offer
+fn conflict(tx: &mut std::boxed::Box<pnet::datalink::DataLinkSender>,
+            iface: &mut Interface,
+            index: usize,
+            pending: &mut Vec<PendingOffer>) {
+    let offer = pending.swap_remove(index);
+    let client = lease::get_client(&offer.request.payload.payload.payload);
+    if let Some(au) = iface.allocators.iter_mut().find(|au| au.has_allocation(&client, offer.addr)) {
+        au.conflict(offer.addr);
+    }
+
+    handle_packet(tx, iface, offer.request, pending);
+}
+
+/// Send the offers whose probe didn't get an answer in time
+fn send_pending(tx: &mut std::boxed::Box<pnet::datalink::DataLinkSender>,
+                iface: &Interface,
+                pending: &mut Vec<PendingOffer>) {
+    let now = Instant::now();
+    let (due, waiting): (Vec<_>, Vec<_>) = pending.drain(..).partition(|p| p.deadline <= now);
+    *pending = waiting;
+
+    for offer in due {
+        send_answer(tx, iface, &offer.request, offer.answer, offer.s_ip);
+    }
+}
+
+/// Serve `conf` on a new thread until it's told to stop.
+/// The state is saved a last time and the name of the interface is sent on `done` when the thread ends.
+/// The thread returns an error if it stopped on its own or the last save failed.
 pub fn handle_interface(conf: config::Interface,
-                        cache: String)
-                        -> std::thread::JoinHandle<()> {
+                        options: &[packet::DhcpOption],
+                        classes: &[config::Class],
+                        storage: &config::Storage,
+                        cache: &str,
+                        control: Receiver<Control>,
+                        done: Sender<String>)
+                        -> std::thread::JoinHandle<Result<(), String>> {
 
-    let (mut iface, mut tx, mut rx)  = Interface::get(conf, &cache);
+    let (mut iface, mut tx, mut rx)  = Interface::get(conf, options, classes, storage, cache);
 
     std::thread::spawn(move || {
+        let mut pending = Vec::new();
+        let mut ret = Ok(());
         loop {
             trace!("Going into receive loop");
             match rx.next() {
                 Ok(rec) => {
                     trace!("Received something");
-                    let packet = decode_dhcp(rec);
-                    debug!("{:?}", &packet);
-                    match packet {
-                        Err(_) => {},
-                        Ok(x) => {
-                            handle_packet(&mut tx, &mut iface, x);
-                            iface.save_to(&cache);
-                        },
+                    if let Ok(echo) = decode_echo(rec) {
+                        if !pending.is_empty() {
+                            handle_echo(&mut tx, &mut iface, echo, &mut pending);
+                            let _ = iface.save();
+                        }
+                    } else if let Ok(arp) = decode_arp(rec) {
+                        if !pending.is_empty() {
+                            handle_arp(&mut tx, &mut iface, arp, &mut pending);
+                            let _ = iface.save();
+                        }
+                    } else {
+                        let packet = decode_dhcp(rec);
+                        debug!("{:?}", &packet);
+                        match packet {
+                            Err(_) => {},
+                            // Replies to relays go to the server port, don't answer ourselves
+                            Ok(ref x) if x.src == EthernetAddr::from(&iface.my_mac) => {},
+                            Ok(x) => {
+                                handle_packet(&mut tx, &mut iface, x, &mut pending);
+                                let _ = iface.save();
+                            },
+                        }
                     }
                 }
                 Err(e) => {
-                    if e.kind() == std::io::ErrorKind::Interrupted {
-                        trace!("Ethernet read syscall got interrupted");
-                        continue;
+                    match e.kind() {
+                        std::io::ErrorKind::Interrupted => trace!("Ethernet read syscall got interrupted"),
+                        std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock => {},
+                        _ => {
+                            error!("Failed to read from ethernet socket: {}", e);
+                            ret = Err(format!("Failed to read from {}: {}", iface.name, e));
+                            break;
+                        },
                     }
-                    error!("Failed to read from ethernet socket: {}", e);
-                    break;
                 }
             }
+
+            send_pending(&mut tx, &iface, &mut pending);
+
+            match control.try_recv() {
+                Ok(Control::Reload(conf, options, classes)) => {
+                    iface.reload(conf, &options, &classes);
+                    let _ = iface.save();
+                },
+                Ok(Control::Stop) | Err(TryRecvError::Disconnected) => {
+                    info!("Stopping interface {}", iface.name);
+                    break;
+                },
+                Err(TryRecvError::Empty) => {},
+            }
+        }
+
+        if let Err(e) = iface.save() {
+            ret = ret.and(Err(format!("Couldn't save the state of {}: {}", iface.name, e)));
         }
+        let _ = done.send(iface.name.clone());
+        ret
     })
 }
+
+#[cfg(test)]
+mod test {
+    use super::{get_destination, get_request_state, RequestState};
+    use frame::ethernet::EthernetAddr;
+    use packet::{DhcpFlags, DhcpOption, DhcpPacket, PacketType};
+    use std::net::Ipv4Addr;
+
+    fn packet(packet_type: PacketType) -> DhcpPacket<EthernetAddr> {
+        DhcpPacket {
+            packet_type: packet_type,
+            xid: 0,
+            seconds: 0,
+            client_addr: None,
+            your_addr: None,
+            server_addr: None,
+            gateway_addr: None,
+            client_hwaddr: EthernetAddr([0, 0, 0, 0, 0, 1]),
+            options: Vec::new(),
+            flags: Vec::new(),
+            max_size: None,
+        }
+    }
+
+    #[test]
+    fn answers_relay() {
+        let mut request = packet(PacketType::Discover);
+        request.gateway_addr = Some(Ipv4Addr::new(10, 0, 0, 1));
+        let answer = packet(PacketType::Offer);
+
+        let sender = EthernetAddr([0, 0, 0, 0, 0, 2]);
+        assert!(get_destination(&request, &answer, sender) == (sender, Ipv4Addr::new(10, 0, 0, 1), 67));
+    }
+
+    #[test]
+    fn unicasts_to_ciaddr() {
+        let mut request = packet(PacketType::Request);
+        request.client_addr = Some(Ipv4Addr::new(10, 0, 0, 5));
+        request.flags.push(DhcpFlags::Broadcast);
+        let answer = packet(PacketType::Ack);
+
+        assert!(get_destination(&request, &answer, EthernetAddr([0; 6])) == (request.client_hwaddr, Ipv4Addr::new(10, 0, 0, 5), 68));
+    }
+
+    #[test]
+    fn broadcasts_on_flag() {
+        let mut request = packet(PacketType::Discover);
+        request.flags.push(DhcpFlags::Broadcast);
+        let mut answer = packet(PacketType::Offer);
+        answer.your_addr = Some(Ipv4Addr::new(10, 0, 0, 5));
+
+        assert!(get_destination(&request, &answer, EthernetAddr([0; 6])) == (EthernetAddr([0xff; 6]), Ipv4Addr::new(255, 255, 255, 255), 68));
+    }
+
+    #[test]
+    fn unicasts_to_yiaddr() {
+        let request = packet(PacketType::Discover);
+        let mut answer = packet(PacketType::Offer);
+        answer.your_addr = Some(Ipv4Addr::new(10, 0, 0, 5));
+
+        assert!(get_destination(&request, &answer, EthernetAddr([0; 6])) == (request.client_hwaddr, Ipv4Addr::new(10, 0, 0, 5), 68));
+    }
+
+    #[test]
+    fn broadcasts_nack() {
+        let mut request = packet(PacketType::Request);
+        request.client_addr = Some(Ipv4Addr::new(10, 0, 0, 5));
+        let answer = packet(PacketType::Nack);
+
+        assert!(get_destination(&request, &answer, EthernetAddr([0; 6])) == (EthernetAddr([0xff; 6]), Ipv4Addr::new(255, 255, 255, 255), 68));
+    }
+
+    #[test]
+    fn request_states() {
+        let server = Ipv4Addr::new(10, 0, 0, 1);
+        let addr = Ipv4Addr::new(10, 0, 0, 5);
+        let broadcast = Ipv4Addr::new(255, 255, 255, 255);
+
+        let mut selecting = packet(PacketType::Request);
+        selecting.options = vec![DhcpOption::ServerIdentifier(server), DhcpOption::AddressRequest(addr)];
+        assert!(get_request_state(&selecting, broadcast) == Some(RequestState::Selecting(server, addr)));
+
+        let mut reboot = packet(PacketType::Request);
+        reboot.options = vec![DhcpOption::AddressRequest(addr)];
+        assert!(get_request_state(&reboot, broadcast) == Some(RequestState::InitReboot(addr)));
+
+        let mut renew = packet(PacketType::Request);
+        renew.client_addr = Some(addr);
+        assert!(get_request_state(&renew, server) == Some(RequestState::Renewing(addr)));
+        assert!(get_request_state(&renew, broadcast) == Some(RequestState::Rebinding(addr)));
+
+        assert!(get_request_state(&packet(PacketType::Request), broadcast).is_none());
+    }
+}
@@ -0,0 +1,453 @@
+use std;
+use std::io::Read;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+use time;
+
+use allocationunit;
+use config;
+use frame::ethernet::EthernetAddr;
+use interface;
+use lease;
+use packet;
+use store;
+
+/// A lease from the file of another server
+#[derive(Debug, PartialEq)]
+pub struct Entry {
+    /// Where it is in the file, for the report
+    pub line: usize,
+    pub address: Ipv4Addr,
+    pub client: lease::Client<EthernetAddr>,
+    /// When the lease was handed out or last renewed
+    pub start: time::Timespec,
+    /// Seconds the lease runs, `None` if the client doesn't hold it anymore
+    pub duration: Option<u32>,
+}
+
+/// The lease of a file that never ends
+const FOREVER: u32 = !0;
+
+fn get_duration(start: time::Timespec, end: time::Timespec) -> u32 {
+    let secs = end.sec - start.sec;
+    if secs < 0 { 0 } else if secs > i64::from(FOREVER) { FOREVER } else { secs as u32 }
+}
+
+/// Client identifiers are written as colon separated hex
+fn parse_hex(s: &str) -> Option<Box<[u8]>> {
+    s.split(':').map(|x| u8::from_str_radix(x, 16).ok()).collect::<Option<Vec<u8>>>().map(|x| x.into_boxed_slice())
+}
+
+/// Parse `dnsmasq.leases`, one `expiry mac address hostname client-id` per line.
+/// dnsmasq only keeps the end of a lease, the import takes it as started now.
+pub fn parse_dnsmasq(content: &str) -> (Vec<Entry>, Vec<String>) {
+    let now = time::get_time();
+    let mut entries = Vec::new();
+    let mut problems = Vec::new();
+    let mut ipv6 = false;
+
+    for (i, line) in content.lines().enumerate() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        // The DUID of the server, the IPv6 leases follow it
+        if !fields.is_empty() && fields[0] == "duid" {
+            ipv6 = true;
+        }
+        if fields.is_empty() || ipv6 {
+            continue;
+        }
+        if fields.len() < 4 {
+            problems.push(format!("Line {}: Expected at least 4 fields", i + 1));
+            continue;
+        }
+
+        let address = match Ipv4Addr::from_str(fields[2]) {
+                Ok(x) => x,
+                Err(_) => {
+                    problems.push(format!("Line {}: {} isn't an IPv4 address", i + 1, fields[2]));
+                    continue;
+                },
+            };
+        let hw_addr = match EthernetAddr::from_str(fields[1]) {
+                Ok(x) => x,
+                Err(e) => {
+                    problems.push(format!("Line {}: {}", i + 1, e));
+                    continue;
+                },
+            };
+        let (start, duration) = match i64::from_str(fields[0]) {
+                Ok(0) => (now, Some(FOREVER)),
+                Ok(expiry) if expiry > now.sec => (now, Some(get_duration(now, time::Timespec::new(expiry, 0)))),
+                Ok(expiry) => (time::Timespec::new(expiry, 0), None),
+                Err(_) => {
+                    problems.push(format!("Line {}: Couldn't parse expiry {}", i + 1, fields[0]));
+                    continue;
+                },
+            };
+
+        entries.push(Entry {
+            line: i + 1,
+            address: address,
+            client: lease::Client {
+                hw_addr: hw_addr,
+                client_identifier: fields.get(4).and_then(|x| parse_hex(x)),
+                hostname: if fields[3] == "*" { None } else { Some(fields[3].to_string()) },
+            },
+            start: start,
+            duration: duration,
+        });
+    }
+
+    (entries, problems)
+}
+
+/// Split `dhcpd.leases` into words, quoted strings and `{`, `}` and `;`, with their line.
+/// Quoted strings keep their quotes.
+fn tokenize(content: &str) -> Vec<(usize, String)> {
+    let mut ret = Vec::new();
+    let mut line = 1;
+    let mut chars = content.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => line += 1,
+            '#' => while chars.peek().map(|c| *c != '\n').unwrap_or(false) {
+                    chars.next();
+                },
+            '{' | '}' | ';' => ret.push((line, c.to_string())),
+            '"' => {
+                let mut token = String::from("\"");
+                while let Some(c) = chars.next() {
+                    token.push(c);
+                    match c {
+                        '\\' => if let Some(next) = chars.next() {
+                                token.push(next);
+                            },
+                        '"' => break,
+                        '\n' => line += 1,
+                        _ => {},
+                    }
+                }
+                ret.push((line, token));
+            },
+            c if c.is_whitespace() => {},
+            c => {
+                let mut token = c.to_string();
+                while let Some(&next) = chars.peek() {
+                    if next.is_whitespace() || next == '{' || next == '}' || next == ';' || next == '"' {
+                        break;
+                    }
+                    token.push(next);
+                    chars.next();
+                }
+                ret.push((line, token));
+            },
+        }
+    }
+
+    ret
+}
+
+/// The bytes of a quoted string with octal escapes like `"\001\000\033"`, or of colon separated hex
+fn parse_isc_data(token: &str) -> Option<Box<[u8]>> {
+    if !token.starts_with('"') {
+        return parse_hex(token);
+    }
+
+    let bytes = token.as_bytes();
+    let inner = &bytes[1..bytes.len() - 1];
+    let mut ret = Vec::with_capacity(inner.len());
+    let mut i = 0;
+    while i < inner.len() {
+        if inner[i] != b'\\' || i + 1 >= inner.len() {
+            ret.push(inner[i]);
+            i += 1;
+        } else if i + 4 <= inner.len() && inner[i + 1..i + 4].iter().all(|c| *c >= b'0' && *c <= b'7') {
+            ret.push(inner[i + 1..i + 4].iter().fold(0u16, |acc, c| acc * 8 + u16::from(c - b'0')) as u8);
+            i += 4;
+        } else {
+            ret.push(inner[i + 1]);
+            i += 2;
+        }
+    }
+
+    Some(ret.into_boxed_slice())
+}
+
+/// `never`, `epoch <seconds>` or `<weekday> <yyyy/mm/dd> <hh:mm:ss>` in UTC
+fn parse_isc_time(words: &[String]) -> Result<Option<time::Timespec>, String> {
+    match words.first().map(|x| x.as_str()) {
+        Some("never") => Ok(None),
+        Some("epoch") => words.get(1).and_then(|x| i64::from_str(x).ok())
+            .map(|secs| Some(time::Timespec::new(secs, 0)))
+            .ok_or_else(|| format!("Couldn't parse time {}", words.join(" "))),
+        _ if words.len() == 3 => time::strptime(&format!("{} {}", words[1], words[2]), "%Y/%m/%d %H:%M:%S")
+            .map(|tm| Some(tm.to_timespec()))
+            .map_err(|e| format!("Couldn't parse time {}: {}", words.join(" "), e)),
+        _ => Err(format!("Couldn't parse time {}", words.join(" "))),
+    }
+}
+
+/// The statements of a block, `tokens` starts after the `{`. Nested blocks are skipped.
+/// Returns the statements and the number of tokens used, including the `}`.
+fn read_block(tokens: &[(usize, String)]) -> (Vec<Vec<String>>, usize) {
+    let mut statements = Vec::new();
+    let mut current = Vec::new();
+    let mut depth = 0;
+
+    for (i, token) in tokens.iter().map(|t| &t.1).enumerate() {
+        match token.as_str() {
+            "{" => depth += 1,
+            "}" if depth == 0 => return (statements, i + 1),
+            "}" => {
+                depth -= 1;
+                current.clear();
+            },
+            ";" if depth == 0 => statements.push(current.split_off(0)),
+            _ if depth == 0 => current.push(token.clone()),
+            _ => {},
+        }
+    }
+
+    (statements, tokens.len())
+}
+
+/// Turn the statements of a `lease` block into an entry, `None` for leases without a client
+fn parse_isc_lease(line: usize, address: Ipv4Addr, statements: &[Vec<String>]) -> Result<Option<Entry>, String> {
+    let mut hw_addr = None;
+    let mut client_id = None;
+    let mut hostname = None;
+    let mut starts = None;
+    let mut ends = None;
+    let mut state = String::from("active");
+
+    for words in statements {
+        match words.first().map(|x| x.as_str()) {
+            Some("starts") => starts = parse_isc_time(&words[1..])?,
+            Some("ends") => ends = Some(parse_isc_time(&words[1..])?),
+            Some("hardware") if words.len() == 3 && words[1] == "ethernet" => hw_addr = Some(EthernetAddr::from_str(&words[2])?),
+            Some("uid") if words.len() == 2 => client_id = parse_isc_data(&words[1]),
+            Some("client-hostname") if words.len() == 2 => hostname = Some(words[1].trim_matches('"').to_string()),
+            Some("binding") if words.len() == 3 && words[1] == "state" => state = words[2].clone(),
+            _ => {},
+        }
+    }
+
+    let hw_addr = match hw_addr {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+    // Abandoned addresses were in use by someone else, backup ones belong to the failover peer
+    if state == "abandoned" || state == "backup" {
+        return Ok(None);
+    }
+
+    let start = starts.unwrap_or_else(time::get_time);
+    let duration = match ends {
+            _ if state != "active" => None,
+            Some(None) | None => Some(FOREVER),
+            Some(Some(end)) => Some(get_duration(start, end)),
+        };
+
+    Ok(Some(Entry {
+        line: line,
+        address: address,
+        client: lease::Client { hw_addr: hw_addr, client_identifier: client_id, hostname: hostname },
+        start: start,
+        duration: duration,
+    }))
+}
+
+/// Parse `dhcpd.leases` of ISC dhcpd. The file is a log, a later entry for an address replaces earlier ones.
+pub fn parse_isc(content: &str) -> (Vec<Entry>, Vec<String>) {
+    let tokens = tokenize(content);
+    let mut entries: Vec<Entry> = Vec::new();
+    let mut problems = Vec::new();
+
+    let mut i = 0;
+    while i < tokens.len() {
+        let (line, ref token) = tokens[i];
+        // Skip everything that isn't an IPv4 lease, up to the end of its statement or block
+        if token != "lease" || tokens.get(i + 2).map(|t| t.1 != "{").unwrap_or(true) {
+            while i < tokens.len() && tokens[i].1 != ";" && tokens[i].1 != "{" {
+                i += 1;
+            }
+            if i < tokens.len() && tokens[i].1 == "{" {
+                i += 1 + read_block(&tokens[i + 1..]).1;
+            } else {
+                i += 1;
+            }
+            continue;
+        }
+
+        let (statements, used) = read_block(&tokens[i + 3..]);
+        let address = &tokens[i + 1].1;
+        let res = Ipv4Addr::from_str(address).map_err(|_| format!("{} isn't an IPv4 address", address))
+            .and_then(|address| parse_isc_lease(line, address, &statements));
+        i += 3 + used;
+
+        match res {
+            Ok(Some(entry)) => {
+                entries.retain(|e| e.address != entry.address);
+                entries.push(entry);
+            },
+            Ok(None) => {},
+            Err(e) => problems.push(format!("Line {}: {}", line, e)),
+        }
+    }
+
+    (entries, problems)
+}
+
+/// Import the leases in `path` into the state of the pools in `conf`.
+/// The server has to be stopped, it would overwrite the state with its own.
+/// Returns the exit status.
+pub fn run(conf: config::Config, path: &str, format: &str) -> i32 {
+    let config_problems = conf.verify();
+    if !config_problems.is_empty() {
+        for problem in &config_problems {
+            println!("{}", problem);
+        }
+        return 1;
+    }
+
+    // The server would overwrite what we import with its own state
+    let _lock = match store::lock(&conf.cache_dir) {
+            Ok(x) => x,
+            Err(e) => {
+                println!("Couldn't lock {}: {}", conf.cache_dir, e);
+                return 1;
+            },
+        };
+
+    let mut content = String::new();
+    if let Err(e) = std::fs::File::open(path).and_then(|mut file| file.read_to_string(&mut content)) {
+        println!("Couldn't read {}: {}", path, e);
+        return 1;
+    }
+
+    let (mut entries, mut problems) = match format {
+            "dnsmasq" => parse_dnsmasq(&content),
+            _ => parse_isc(&content),
+        };
+    // Clients move to the address they got last
+    entries.sort_by_key(|e| e.start);
+
+    let mut status = 0;
+    let mut placed = vec![false; entries.len()];
+    let mut imported = 0;
+    let classes = conf.classes;
+    for iface in conf.interfaces {
+        let name = iface.name;
+        let mut inherited = conf.options.clone();
+        packet::merge_options(&mut inherited, &iface.options);
+
+        let mut store = match store::open(&conf.storage, &conf.cache_dir, &name) {
+                Ok(x) => x,
+                Err(e) => {
+                    println!("Couldn't open the lease storage for {}: {}", name, e);
+                    return 1;
+                },
+            };
+        let mut units: Vec<allocationunit::AllocationUnit> = iface.pool.into_iter()
+            .map(|x| allocationunit::AllocationUnit::configure(x, &name, &inherited, &classes))
+            .collect();
+        interface::adopt_range_names(&units, &mut *store, &name);
+
+        for unit in units.iter_mut() {
+            unit.load_or_exit(&name, &mut *store);
+            for (i, entry) in entries.iter().enumerate() {
+                if placed[i] || !unit.in_range(entry.address) {
+                    continue;
+                }
+
+                placed[i] = true;
+                match unit.import(&entry.client, entry.address, entry.start, entry.duration) {
+                    Ok(()) => imported += 1,
+                    Err(e) => problems.push(format!("Line {}: {}", entry.line, e)),
+                }
+            }
+
+            if let Err(e) = unit.save_to(&mut *store) {
+                println!("Couldn't save pool {} on {}: {}", unit.get_name(), name, e);
+                status = 1;
+            }
+        }
+    }
+
+    for (entry, _) in entries.iter().zip(placed.iter()).filter(|&(_, placed)| !*placed) {
+        problems.push(format!("Line {}: {} for {} fits no pool", entry.line, entry.address, entry.client.hw_addr));
+    }
+
+    println!("Imported {} of {} leases from {}", imported, entries.len(), path);
+    for problem in &problems {
+        println!("{}", problem);
+    }
+
+    status
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_dnsmasq, parse_isc, FOREVER};
+    use frame::ethernet::EthernetAddr;
+    use std::net::Ipv4Addr;
+    use time;
+
+    #[test]
+    fn parses_isc() {
+        let content = r#"
+# The format of this file is documented in the dhcpd.leases(5) manual page.
+authoring-byte-order little-endian;
+server-duid "\000\001\000\001";
+
+lease 10.0.0.5 {
+  starts 2 2019/01/01 10:00:00;
+  ends never;
+  binding state active;
+  hardware ethernet 00:1a:2b:3c:4d:5e;
+  uid "\001\000\032+<M^";
+  client-hostname "laptop";
+}
+lease 10.0.0.6 {
+  starts epoch 1546336800; # 2019/01/01 10:00:00
+  ends epoch 1546340400;
+  binding state free;
+  hardware ethernet 00:1a:2b:3c:4d:5f;
+  on expiry { set foo = "bar"; }
+}
+lease 10.0.0.7 {
+  binding state abandoned;
+  hardware ethernet 00:1a:2b:3c:4d:60;
+}
+lease 10.0.0.x {
+  hardware ethernet 00:1a:2b:3c:4d:61;
+}
+"#;
+        let (entries, problems) = parse_isc(content);
+
+        assert!(problems.len() == 1);
+        assert!(entries.len() == 2);
+        assert!(entries[0].address == Ipv4Addr::new(10, 0, 0, 5) && entries[0].line == 6);
+        assert!(entries[0].client.hw_addr == EthernetAddr([0x00, 0x1a, 0x2b, 0x3c, 0x4d, 0x5e]));
+        assert!(entries[0].client.client_identifier.as_ref().map(|x| &x[..]) == Some(&[1, 0, 0x1a, 0x2b, 0x3c, 0x4d, 0x5e][..]));
+        assert!(entries[0].client.hostname == Some(String::from("laptop")));
+        assert!(entries[0].start == time::Timespec::new(1546336800, 0) && entries[0].duration == Some(FOREVER));
+        assert!(entries[1].start == time::Timespec::new(1546336800, 0) && entries[1].duration.is_none());
+    }
+
+    #[test]
+    fn parses_dnsmasq() {
+        let content = "0 00:1a:2b:3c:4d:5e 10.0.0.5 laptop 01:00:1a:2b:3c:4d:5e\n\
+                       1546340400 00:1a:2b:3c:4d:5f 10.0.0.6 * *\n\
+                       duid 00:01:00:01:23:45:67:89\n\
+                       1546340400 1234 fd00::5 * 00:01\n";
+        let (entries, problems) = parse_dnsmasq(content);
+
+        assert!(problems.is_empty());
+        assert!(entries.len() == 2);
+        assert!(entries[0].duration == Some(FOREVER) && entries[0].client.hostname == Some(String::from("laptop")));
+        assert!(entries[0].client.client_identifier.as_ref().map(|x| x.len()) == Some(7));
+        assert!(entries[1].address == Ipv4Addr::new(10, 0, 0, 6) && entries[1].duration.is_none());
+        assert!(entries[1].start == time::Timespec::new(1546340400, 0) && entries[1].client.hostname.is_none());
+    }
+}
@@ -3,44 +3,83 @@ use ipnetwork;
 use std::net::Ipv4Addr;
 use pnet::datalink::{self, NetworkInterface};
 use pnet::datalink::Channel;
-use std::path::Path;
-use std::fmt::Display;
+use std::time::Duration;
 
 use allocationunit;
 use pnet;
 use config;
+use packet;
+use store;
+
+/// Pools that got a name still have their state under the name of their range.
+/// The state is moved to the name if it's the one of the current range, or the only unused one
+/// sharing addresses with it. Loading drops what's outside the range.
+pub fn adopt_range_names(units: &[allocationunit::AllocationUnit], store: &mut store::LeaseStore, iface: &str) {
+    let saved = match store.pools() {
+            Ok(x) => x,
+            Err(e) => {
+                error!("Couldn't list the saved pools of {}: {}", iface, e);
+                return;
+            },
+        };
+    let mut unused: Vec<&String> = saved.iter().filter(|name| !units.iter().any(|u| u.get_name() == **name)).collect();
+
+    for unit in units.iter().filter(|u| u.has_own_name() && !saved.contains(&u.get_name())) {
+        let range_name = unit.get_range_name();
+        let candidates: Vec<usize> = match unused.iter().position(|name| **name == range_name) {
+                Some(i) => vec![i],
+                None => (0..unused.len()).filter(|i| unit.overlaps_range_name(unused[*i])).collect(),
+            };
+        if candidates.len() > 1 {
+            warn!("Not moving saved state to pool {} on {}, it overlaps {} saved ranges", unit.get_name(), iface, candidates.len());
+            continue;
+        }
+
+        if let Some(&i) = candidates.first() {
+            let old = unused.remove(i);
+            match store.rename(old, &unit.get_name()) {
+                Ok(()) => info!("Moved the saved state of {} on {} to pool {}", old, iface, unit.get_name()),
+                Err(e) => error!("Couldn't move the saved state of {} on {} to pool {}: {}", old, iface, unit.get_name(), e),
+            }
+        }
+    }
+}
 
 pub struct Interface {
     pub allocators: Box<[allocationunit::AllocationUnit]>,
     pub name: String,
     pub my_mac: pnet::datalink::MacAddr,
-    pub my_ip: Vec<Ipv4Addr>
+    pub my_ip: Vec<Ipv4Addr>,
+    store: Box<store::LeaseStore>,
 }
 
 impl Interface {
-    pub fn save_to<D: AsRef<Path> + Display>(&self, dir: D) {
-        info!("Saving interface {}", &self.name);
-        if !(dir.as_ref().exists() && dir.as_ref().is_dir()){
-            warn!("Target directory for saving interface {} didn't exist", &self.name);
-            return;
-        }
-
-        let my_dir = dir.as_ref().join(&self.name);
-        let _ = std::fs::create_dir_all(my_dir.as_path()).map_err(|e| {
-                error!("Couldn't create storage directory for interface: {} in {}: {}", &self.name, dir, e);
-                ()
-            });
+    /// Save all allocators, errors are logged. The first one is returned.
+    pub fn save(&mut self) -> std::io::Result<()> {
+        debug!("Saving interface {}", &self.name);
+        let mut ret = Ok(());
 
-        for alloc in self.allocators.iter() {
-            let _ = alloc.save_to(my_dir.as_path()).map_err(|_| {
-                error!("Encountered error while storing allocator {} on {}", alloc.get_name(), self.name);
-                ()
+        let name = &self.name;
+        let store = &mut self.store;
+        for alloc in self.allocators.iter_mut() {
+            let res = alloc.save_to(&mut **store).map_err(|e| {
+                error!("Encountered error while storing allocator {} on {}", alloc.get_name(), name);
+                e
                 });
+            if ret.is_ok() {
+                ret = res;
+            }
         }
+
+        ret
     }
 
     /// This requires `CAP_NET_ADMIN`
-    pub fn get<D: AsRef<Path> + Display>(conf: config::Interface, dir: D)
+    pub fn get(conf: config::Interface,
+               options: &[packet::DhcpOption],
+               classes: &[config::Class],
+               storage: &config::Storage,
+               cache_dir: &str)
             -> (Interface, Box<pnet::datalink::DataLinkSender>, Box<pnet::datalink::DataLinkReceiver>) {
         let interfaces = datalink::interfaces();
         let interface = match interfaces.into_iter().find(|iface: &NetworkInterface | iface.name == conf.name.as_str()) {
@@ -56,7 +95,9 @@ impl Interface {
 
         debug!("Trying to open interface: {}", &conf.name);
 
-        let (tx, rx) = match datalink::channel(&interface, Default::default()) {
+        // Wake up regularly, offers may be waiting for a probe to time out
+        let channel_conf = datalink::Config { read_timeout: Some(Duration::from_millis(100)), ..Default::default() };
+        let (tx, rx) = match datalink::channel(&interface, channel_conf) {
             Ok(Channel::Ethernet(tx, rx)) => (tx, rx),
             Ok(_) => panic!("Unhandled channel type!"),
             Err(e) => panic!("An error occured while creating ethernet channel: {}", e)
@@ -64,8 +105,22 @@ impl Interface {
         // I love/hate this silly borrow checker and the workarounds I come up with
         let name = conf.name;
         let pool = conf.pool;
+        let mut inherited = options.to_vec();
+        packet::merge_options(&mut inherited, &conf.options);
 
-        let allocs: Vec<allocationunit::AllocationUnit> = pool.into_iter().map(|x| allocationunit::AllocationUnit::from_conf(x, &name, &dir)).collect();
+        let mut store = match store::open(storage, cache_dir, &name) {
+                Ok(x) => x,
+                Err(e) => {
+                    error!("Couldn't open the lease storage for {}: {}", name, e);
+                    println!("Couldn't open the lease storage for {}: {}", name, e);
+                    std::process::exit(1)
+                }
+            };
+        let mut allocs: Vec<allocationunit::AllocationUnit> = pool.into_iter().map(|x| allocationunit::AllocationUnit::configure(x, &name, &inherited, classes)).collect();
+        adopt_range_names(&allocs, &mut *store, &name);
+        for alloc in allocs.iter_mut() {
+            alloc.load_or_exit(&name, &mut *store);
+        }
         let ip = interface.ips.into_iter().flat_map(|x| match x {
                 ipnetwork::IpNetwork::V4(net) => Some(net.ip()),
                 _ => None,
@@ -75,12 +130,93 @@ impl Interface {
             name: name,
             my_mac: mac,
             my_ip: ip,
-            allocators: allocs.into_boxed_slice()
+            allocators: allocs.into_boxed_slice(),
+            store: store,
             };
         info!("Using interface {} with local mac {} and ips {:?}", &ret.name, &ret.my_mac, &ret.my_ip);
 
         (ret, tx, rx)
     }
 
+    /// Apply a changed configuration, the channel and addresses stay as they are.
+    /// Pools with an unchanged name keep their state. The state of changed or removed pools
+    /// moves to the new pools covering the addresses.
+    pub fn reload(&mut self,
+                  conf: config::Interface,
+                  options: &[packet::DhcpOption],
+                  classes: &[config::Class]) {
+        info!("Reloading interface {}", self.name);
+        let mut inherited = options.to_vec();
+        packet::merge_options(&mut inherited, &conf.options);
+
+        let mut units: Vec<allocationunit::AllocationUnit> = conf.pool.into_iter()
+            .map(|x| allocationunit::AllocationUnit::configure(x, &self.name, &inherited, classes))
+            .collect();
+
+        // Pools that got a name take over the state saved under their range, so it has to be current.
+        // Errors are logged, the state is still adopted from the old pools below.
+        let _ = self.save();
+        adopt_range_names(&units, &mut *self.store, &self.name);
+        let mut old = std::mem::replace(&mut self.allocators, Box::new([])).into_vec();
+
+        // Take the units with the same range first, so their state doesn't end up somewhere else
+        let mut fresh = Vec::new();
+        for (i, unit) in units.iter_mut().enumerate() {
+            match old.iter().position(|x| x.get_name() == unit.get_name()) {
+                Some(j) => {
+                    let mut prev = old.swap_remove(j);
+                    unit.adopt(&mut prev);
+                },
+                None => fresh.push(i),
+            }
+        }
+
+        let mut unusable = Vec::new();
+        for i in fresh {
+            let unit = &mut units[i];
+            info!("Adding pool {} on {}", unit.get_name(), self.name);
+            if let Err(e) = unit.load(&self.name, &mut *self.store) {
+                error!("Couldn't read allocator {} on interface {}: {}", unit.get_name(), self.name, e);
+                // Saving would overwrite what the newer server wrote
+                if store::format::is_newer(&e) {
+                    error!("Not serving pool {} on {}", unit.get_name(), self.name);
+                    unusable.push(i);
+                    continue;
+                }
+            }
+            for prev in old.iter_mut() {
+                unit.adopt(prev);
+            }
+        }
+        for i in unusable.into_iter().rev() {
+            units.remove(i);
+        }
+
+        for prev in &old {
+            if prev.is_empty() {
+                info!("Removed pool {} from {}", prev.get_name(), self.name);
+            } else {
+                warn!("Removed pool {} from {}, dropping the allocations no other pool covers", prev.get_name(), self.name);
+            }
+        }
+
+        for unit in units.iter_mut() {
+            unit.apply_reservations();
+        }
+        self.allocators = units.into_boxed_slice();
+
+        // A pool with the same name or range would get the old leases back.
+        // The adopted ones have to be saved with their new pools before, a crash would lose them otherwise.
+        if self.save().is_err() {
+            error!("Keeping the saved state of the removed pools on {}, the pools that took it over couldn't be saved", self.name);
+            return;
+        }
+        for prev in &old {
+            if let Err(e) = self.store.remove(&prev.get_name()) {
+                error!("Couldn't remove the saved state of pool {} on {}: {}", prev.get_name(), self.name, e);
+            }
+        }
+    }
+
 }
 
@@ -12,6 +12,8 @@ pub struct SerializeableTime(pub time::Timespec);
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct Client<H> {
     pub hw_addr: H,
+    /// Stored under a new name, older lease files kept the vendor class in `client_identifier`
+    #[serde(rename = "client_id")]
     pub client_identifier: Option<Box<[u8]>>,
     pub hostname: Option<String>
 }
@@ -47,7 +49,7 @@ impl<'a> serde::Deserialize<'a> for SerializeableTime {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Lease<H, I> {
     pub assigned: I,
     pub client: Client<H>,
@@ -55,17 +57,30 @@ pub struct Lease<H, I> {
     pub lease_duration: u32
 }
 
-fn def_false() -> bool { false }
-
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Allocation<H, I> {
     pub assigned: I,
     pub client: Client<H>,
     pub last_seen: SerializeableTime,
-    #[serde(default = "def_false")]
     pub forever: bool,
 }
 
+/// An address that was declined by a client and is kept out of the pool for a while
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Quarantine<H, I> {
+    pub assigned: I,
+    pub client: Client<H>,
+    pub start: SerializeableTime,
+    pub duration: u32
+}
+
+impl<H, I> Quarantine<H, I> {
+    pub fn is_active(&self) -> bool {
+        let passed = time::get_time() - self.start.0;
+        passed < time::Duration::seconds(i64::from(self.duration))
+    }
+}
+
 impl<H, I> Lease<H, I>
     where H: Eq,
           I: Eq {
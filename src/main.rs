@@ -12,6 +12,7 @@ extern crate rs_config;
 extern crate clap;
 
 extern crate syslog;
+extern crate libc;
 extern crate pnet;
 extern crate time;
 extern crate ipnetwork;
@@ -27,13 +28,27 @@ mod packet;
 mod pool;
 mod serialize;
 mod allocator;
+mod store;
 mod config;
 mod allocationunit;
 mod interface;
 mod handler;
+mod signal;
+mod import;
 
-use clap::{Arg, App};
+use clap::{Arg, App, SubCommand};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
 use std::str::FromStr;
+use std::sync::mpsc;
+use std::time::Duration;
+
+/// An interface thread and the way to talk to it
+struct Running {
+    control: mpsc::Sender<handler::Control>,
+    thread: std::thread::JoinHandle<Result<(), String>>,
+}
 
 // This asumes linux! are there proper compile macros for this?
 #[cfg(feature="dropcaps")]
@@ -92,13 +107,123 @@ fn drop_user() {
     }
 }
 
-fn run_server(path: &str) {
+/// Copy the config at `path` to a file only we can write, so the check and the reload read the same content
+fn copy_config(path: &str) -> std::io::Result<std::path::PathBuf> {
+    let mut content = Vec::new();
+    std::fs::File::open(path)?.read_to_end(&mut content)?;
+
+    let copy = std::env::temp_dir().join(format!("dhcpd-{}.conf", std::process::id()));
+    let _ = std::fs::remove_file(&copy);
+    let mut file = std::fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(&copy)?;
+    file.write_all(&content)?;
+
+    Ok(copy)
+}
+
+/// Check the config at `path` in a child process, `rs_config` exits on errors we want to survive
+fn check_config(path: &str) -> bool {
+    // current_exe() points to a " (deleted)" file once the binary was upgraded, this still works
+    let output = std::process::Command::new("/proc/self/exe")
+                    .arg("--verify")
+                    .arg("--config")
+                    .arg(path)
+                    .output();
+
+    match output {
+        Ok(ref out) if out.status.success() => true,
+        Ok(out) => {
+            for line in String::from_utf8_lossy(&out.stdout).lines().chain(String::from_utf8_lossy(&out.stderr).lines()) {
+                if !line.starts_with("Conf: ") {
+                    error!("{}", line);
+                }
+            }
+            false
+        },
+        Err(e) => {
+            error!("Couldn't run the config check: {}", e);
+            false
+        },
+    }
+}
+
+/// Re-read the config and hand it to the interface threads.
+/// The running config stays if the new one has errors.
+fn reload(path: &str, cache_dir: &str, storage: &config::Storage, running: &mut HashMap<String, Running>) {
+    info!("Reloading config from {}", path);
+    let copy = match copy_config(path) {
+            Ok(x) => x,
+            Err(e) => {
+                error!("Keeping the running config, couldn't copy {}: {}", path, e);
+                return;
+            },
+        };
+    let copy_path = copy.to_string_lossy().into_owned();
+
+    // Nobody else can change the copy, it can't fail here if it passed the check
+    let conf: Option<config::Config> = if check_config(&copy_path) {
+            Some(rs_config::read_or_exit(&copy_path))
+        } else {
+            None
+        };
+    let _ = std::fs::remove_file(&copy);
+    let conf = match conf {
+            Some(x) => x,
+            None => {
+                error!("Keeping the running config, {} has errors", path);
+                return;
+            },
+        };
+
+    if conf.cache_dir != cache_dir {
+        warn!("Changing cache_dir needs a restart, still using {}", cache_dir);
+    }
+    if conf.storage != *storage {
+        warn!("Changing the storage needs a restart, still using {:?}", storage);
+    }
+
+    let names: Vec<String> = conf.interfaces.iter().map(|iface| iface.name.clone()).collect();
+    for iface in conf.interfaces {
+        match running.get(&iface.name) {
+            // We dropped the privileges to open new channels
+            None => warn!("Adding interface {} needs a restart", iface.name),
+            Some(run) => {
+                let _ = run.control.send(handler::Control::Reload(iface, conf.options.clone(), conf.classes.clone()));
+            },
+        }
+    }
+
+    for (name, run) in running.iter() {
+        if !names.contains(name) {
+            let _ = run.control.send(handler::Control::Stop);
+        }
+    }
+}
+
+/// Serve until we're told to stop or all interfaces failed.
+/// Returns the exit status, 0 if everything was saved on a requested shutdown.
+fn run_server(path: &str) -> i32 {
     let conf: config::Config = rs_config::read_or_exit(path);
 
     syslog::init(syslog::Facility::LOG_DAEMON,
                  conf.log_level.to_log_level_filter(),
                  Some("dhcpd")).unwrap();
     info!("Starting up dhcp server");
+    for warning in conf.warnings() {
+        warn!("{}", warning);
+    }
+    signal::install();
+
+    // Held until we exit, so nobody imports leases behind our back
+    let _lock = match store::lock(&conf.cache_dir) {
+            Ok(x) => x,
+            Err(e) => {
+                error!("Couldn't lock {}: {}", conf.cache_dir, e);
+                return 1;
+            },
+        };
+
+    // We change the cwd, but still want to find the config on reload
+    let path = std::fs::canonicalize(path).map(|p| p.to_string_lossy().into_owned()).unwrap_or_else(|_| path.to_string());
 
     trace!("Changing to / cwd");
     match std::env::set_current_dir("/") {
@@ -109,24 +234,79 @@ fn run_server(path: &str) {
     }
 
     let cache_dir = conf.cache_dir;
+    let storage = conf.storage;
+    let options = conf.options;
+    let classes = conf.classes;
 
-    let threads: Vec<std::thread::JoinHandle<()>> =
+    let (done_tx, done_rx) = mpsc::channel();
+    let mut running: HashMap<String, Running> =
             conf.interfaces.into_iter()
-            .map(|iface| handler::handle_interface(iface, cache_dir.clone()))
+            .map(|iface| {
+                let (control, control_rx) = mpsc::channel();
+                let name = iface.name.clone();
+                let thread = handler::handle_interface(iface, &options, &classes, &storage, &cache_dir, control_rx, done_tx.clone());
+                (name, Running { control: control, thread: thread })
+            })
             .collect();
 
     drop_user();
 
-    for thread in threads {
-        let _ = thread.join();
+    let mut stopping = false;
+    let mut status = 0;
+    while !running.is_empty() {
+        match done_rx.recv_timeout(Duration::from_millis(200)) {
+            Ok(name) => {
+                if let Some(run) = running.remove(&name) {
+                    match run.thread.join() {
+                        Ok(Ok(())) => {},
+                        Ok(Err(e)) => {
+                            error!("{}", e);
+                            status = 1;
+                        },
+                        Err(_) => {
+                            error!("The thread for {} panicked", name);
+                            status = 1;
+                        },
+                    }
+                }
+            },
+            Err(_) => {
+                if signal::shutdown_requested() && !stopping {
+                    info!("Shutting down");
+                    stopping = true;
+                    for run in running.values() {
+                        let _ = run.control.send(handler::Control::Stop);
+                    }
+                } else if !stopping && signal::take_reload() {
+                    reload(&path, &cache_dir, &storage, &mut running);
+                }
+            },
+        }
     }
 
+    if !stopping {
+        error!("All interfaces stopped");
+        status = 1;
+    }
+    info!("Exiting with status {}", status);
+    status
 }
 
 fn verify_config(path: &str) {
     let conf: config::Config = rs_config::read_or_exit(path);
 
     println!("Conf: {:?}", conf);
+
+    for warning in conf.warnings() {
+        println!("Warning: {}", warning);
+    }
+    let problems = conf.verify();
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    if !problems.is_empty() {
+        std::process::exit(1);
+    }
 }
 
 
@@ -144,13 +324,29 @@ fn main() {
             .arg(Arg::with_name("verify")
                  .long("verify")
                  .help("Verify the config and exit"))
+            .subcommand(SubCommand::with_name("import")
+                 .about("Import the leases of ISC dhcpd or dnsmasq into the pools of the config. Stop the server first and run it as the user the server runs as")
+                 .arg(Arg::with_name("format")
+                      .long("format")
+                      .value_name("FORMAT")
+                      .help("Format of the lease file")
+                      .possible_values(&["isc", "dnsmasq"])
+                      .required(true)
+                      .takes_value(true))
+                 .arg(Arg::with_name("FILE")
+                      .help("The dhcpd.leases or dnsmasq.leases file")
+                      .required(true)
+                      .index(1)))
             .get_matches();
 
     let path = matches.value_of("config").unwrap_or("/etc/dhcp/dhcpd.conf");
 
     if matches.is_present("verify") {
         verify_config(path);
+    } else if let Some(import) = matches.subcommand_matches("import") {
+        let conf: config::Config = rs_config::read_or_exit(path);
+        std::process::exit(import::run(conf, import.value_of("FILE").unwrap(), import.value_of("format").unwrap()));
     } else {
-        run_server(path);
+        std::process::exit(run_server(path));
     }
 }
@@ -3,12 +3,15 @@ extern crate byteorder;
 
 mod name;
 use self::name::DomainNames;
+#[cfg(test)]
+use self::name::DomainName;
 
 use rs_config::ConfigAble;
 
 use self::byteorder::{WriteBytesExt, NetworkEndian, ByteOrder};
 
 use std::boxed::Box;
+use std::cmp;
 use std::iter;
 use std::option::Option;
 use std::result::Result;
@@ -224,6 +227,85 @@ impl Arbitrary for ClasslessRoute {
     }
 }
 
+/// Sub-options of the relay agent information option (RFC 3046)
+#[derive(Debug, Clone, PartialEq, Eq, ConfigAble)]
+pub enum RelaySubOption {
+    CircuitId(Box<[u8]>),
+    RemoteId(Box<[u8]>),
+    /// RFC 3527, the subnet the relay wants us to assign from
+    LinkSelection(Ipv4Addr),
+    Unknown(u8, Box<[u8]>),
+}
+
+#[cfg(test)]
+impl Arbitrary for RelaySubOption {
+    fn arbitrary<G: Gen>(gen: &mut G) -> Self {
+        let vec: Vec<u8> = Arbitrary::arbitrary(gen);
+        let vec2: Vec<u8> = vec.into_iter().take(32).collect();
+        match u8::arbitrary(gen) % 4 {
+            0 => RelaySubOption::CircuitId(vec2.into_boxed_slice()),
+            1 => RelaySubOption::RemoteId(vec2.into_boxed_slice()),
+            2 => RelaySubOption::LinkSelection(Arbitrary::arbitrary(gen)),
+            3 => RelaySubOption::Unknown(9, vec2.into_boxed_slice()),
+            _ => panic!("Hit impossible case!"),
+        }
+    }
+}
+
+impl RelaySubOption {
+    pub fn get_type(&self) -> u8 {
+        match *self {
+            RelaySubOption::CircuitId(_) => 1,
+            RelaySubOption::RemoteId(_) => 2,
+            RelaySubOption::LinkSelection(_) => 5,
+            RelaySubOption::Unknown(x, _) => x,
+        }
+    }
+
+    fn get_size(&self) -> usize {
+        match *self {
+            RelaySubOption::CircuitId(ref b) | RelaySubOption::RemoteId(ref b) => b.len(),
+            RelaySubOption::LinkSelection(_) => 4,
+            RelaySubOption::Unknown(_, ref b) => b.len(),
+        }
+    }
+
+    /// Whether the length fits the one byte length field
+    fn fits(&self) -> bool {
+        self.get_size() <= 255
+    }
+
+    fn push_value(&self, buffer: &mut Vec<u8>) {
+        match *self {
+            RelaySubOption::CircuitId(ref b) | RelaySubOption::RemoteId(ref b) => buffer.extend(b.iter()),
+            RelaySubOption::LinkSelection(ref ip) => ip.push_to(buffer),
+            RelaySubOption::Unknown(_, ref b) => buffer.extend(b.iter()),
+        }
+    }
+
+    /// The content of the sub-option as it's sent on the wire
+    pub fn get_value(&self) -> Vec<u8> {
+        let mut ret = Vec::with_capacity(self.get_size());
+        self.push_value(&mut ret);
+        ret
+    }
+
+    fn push_to(&self, buffer: &mut Vec<u8>) {
+        buffer.push(self.get_type());
+        buffer.push(self.get_size() as u8);
+        self.push_value(buffer);
+    }
+
+    fn from_buffer(variant: u8, buffer: &[u8]) -> Result<Self, String> {
+        match variant {
+            1 => Ok(RelaySubOption::CircuitId(DhcpOption::bytes_from_buffer(buffer))),
+            2 => Ok(RelaySubOption::RemoteId(DhcpOption::bytes_from_buffer(buffer))),
+            5 => Ok(RelaySubOption::LinkSelection(DhcpOption::ipv4_from_buffer(buffer)?)),
+            _ => Ok(RelaySubOption::Unknown(variant, DhcpOption::bytes_from_buffer(buffer))),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, ConfigAble)]
 pub enum DhcpOption {
     SubnetMask(Ipv4Addr), //This should probably be a better type
@@ -236,10 +318,14 @@ pub enum DhcpOption {
     AddressRequest(Ipv4Addr),
     MessageType(PacketType),
     ServerIdentifier(Ipv4Addr),
+    ParameterRequestList(Box<[u8]>),
     Message(String),
+    MaxMessageSize(u16),
     RenewalTime(u32),
     RebindingTime(u32),
+    VendorClassIdentifier(Box<[u8]>),
     ClientIdentifier(Box<[u8]>),
+    RelayAgentInformation(Box<[RelaySubOption]>),
     DomainSearch(DomainNames),
     ClasslessRoutes(Box<[ClasslessRoute]>),
     Unknown(u8, Box<[u8]>),
@@ -248,17 +334,15 @@ pub enum DhcpOption {
 #[cfg(test)]
 impl Arbitrary for DhcpOption {
     fn arbitrary<G: Gen>(gen: &mut G) -> Self {
-        match u8::arbitrary(gen) % 16 {
+        match u8::arbitrary(gen) % 21 {
             0  => DhcpOption::SubnetMask(Arbitrary::arbitrary(gen)),
             1  => {
                 let vec: Vec<Ipv4Addr> = Arbitrary::arbitrary(gen);
-                let vec2: Vec<Ipv4Addr> = vec.into_iter().take(32).collect();
-                DhcpOption::Router(vec2.into_boxed_slice())
+                DhcpOption::Router(vec.into_boxed_slice())
             },
             2  => {
                 let vec: Vec<Ipv4Addr> = Arbitrary::arbitrary(gen);
-                let vec2: Vec<Ipv4Addr> = vec.into_iter().take(32).collect();
-                DhcpOption::DomainNameServer(vec2.into_boxed_slice())
+                DhcpOption::DomainNameServer(vec.into_boxed_slice())
             },
             3  => DhcpOption::Hostname(Arbitrary::arbitrary(gen)),
             4  => DhcpOption::DomainName(Arbitrary::arbitrary(gen)),
@@ -275,18 +359,36 @@ impl Arbitrary for DhcpOption {
                 let vec2: Vec<u8> = vec.into_iter().take(255).collect();
                 DhcpOption::ClientIdentifier(vec2.into_boxed_slice())
             },
-            //TODO: Reenable when the long option parsing is in
-            //13 => DhcpOption::DomainSearch(/*Arbitrary::arbitrary(gen)*/vec![]),
             14 => {
                 let vec: Vec<ClasslessRoute> = Arbitrary::arbitrary(gen);
-                let vec2: Vec<ClasslessRoute> = vec.into_iter().take(28).collect();
-                DhcpOption::ClasslessRoutes(vec2.into_boxed_slice())
+                DhcpOption::ClasslessRoutes(vec.into_boxed_slice())
             },
             15 => {
                 let vec: Vec<u8> = Arbitrary::arbitrary(gen);
                 let vec2: Vec<u8> = vec.into_iter().take(255).collect();
                 DhcpOption::Unknown(13 as u8, vec2.into_boxed_slice())
             },
+            16 => {
+                let vec: Vec<RelaySubOption> = Arbitrary::arbitrary(gen);
+                let vec2: Vec<RelaySubOption> = vec.into_iter().take(7).collect();
+                DhcpOption::RelayAgentInformation(vec2.into_boxed_slice())
+            },
+            17 => {
+                let vec: Vec<u8> = Arbitrary::arbitrary(gen);
+                let vec2: Vec<u8> = vec.into_iter().take(255).collect();
+                DhcpOption::ParameterRequestList(vec2.into_boxed_slice())
+            },
+            18 => DhcpOption::MaxMessageSize(Arbitrary::arbitrary(gen)),
+            19 => {
+                let vec: Vec<DomainName> = Arbitrary::arbitrary(gen);
+                let vec2: Vec<DomainName> = vec.into_iter().take(8).collect();
+                DhcpOption::DomainSearch(DomainNames::from(vec2))
+            },
+            20 => {
+                let vec: Vec<u8> = Arbitrary::arbitrary(gen);
+                let vec2: Vec<u8> = vec.into_iter().take(255).collect();
+                DhcpOption::VendorClassIdentifier(vec2.into_boxed_slice())
+            },
             _ => panic!("Hit impossible case!"),
         }
     }
@@ -305,31 +407,38 @@ impl DhcpOption {
             DhcpOption::LeaseTime(_) => 51,
             DhcpOption::MessageType(_) => 53,
             DhcpOption::ServerIdentifier(_) => 54,
+            DhcpOption::ParameterRequestList(_) => 55,
             DhcpOption::Message(_) => 56,
+            DhcpOption::MaxMessageSize(_) => 57,
             DhcpOption::RenewalTime(_) => 58,
             DhcpOption::RebindingTime(_) => 59,
-            DhcpOption::ClientIdentifier(_) => 60,
+            DhcpOption::VendorClassIdentifier(_) => 60,
+            DhcpOption::ClientIdentifier(_) => 61,
+            DhcpOption::RelayAgentInformation(_) => 82,
             DhcpOption::DomainSearch(_) => 119,
             DhcpOption::ClasslessRoutes(_) => 121,
             DhcpOption::Unknown(x, _) => x,
         }
     }
 
-    fn get_size(&self) -> u8 {
+    fn get_size(&self) -> usize {
         #![allow(unknown_lints,match_same_arms)]
         match *self {
             DhcpOption::SubnetMask(_) => 4,
-            DhcpOption::Router(ref vec) | DhcpOption::DomainNameServer(ref vec) => 4 * vec.len() as u8,
-            DhcpOption::Hostname(ref str) | DhcpOption::DomainName(ref str) => str.as_bytes().len() as u8,
+            DhcpOption::Router(ref vec) | DhcpOption::DomainNameServer(ref vec) => 4 * vec.len(),
+            DhcpOption::Hostname(ref str) | DhcpOption::DomainName(ref str) => str.as_bytes().len(),
             DhcpOption::BroadcastAddress(_) | DhcpOption::LeaseTime(_) | DhcpOption::AddressRequest(_) => 4,
             DhcpOption::MessageType(_) => 1,
             DhcpOption::ServerIdentifier(_) => 4,
-            DhcpOption::Message(ref str) => str.as_bytes().len() as u8,
+            DhcpOption::ParameterRequestList(ref val) => val.len(),
+            DhcpOption::Message(ref str) => str.as_bytes().len(),
+            DhcpOption::MaxMessageSize(_) => 2,
             DhcpOption::RenewalTime(_) | DhcpOption::RebindingTime(_) => 4,
-            DhcpOption::ClientIdentifier(ref val) => val.len() as u8,
-            DhcpOption::DomainSearch(ref val) => val.byte_len() as u8,
-            DhcpOption::ClasslessRoutes(ref vec) => vec.iter().fold(0, |v, r| v + r.get_size()),
-            DhcpOption::Unknown(_, ref b) => (*b).len() as u8,
+            DhcpOption::VendorClassIdentifier(ref val) | DhcpOption::ClientIdentifier(ref val) => val.len(),
+            DhcpOption::RelayAgentInformation(ref vec) => vec.iter().filter(|o| o.fits()).fold(0, |v, o| v + 2 + o.get_size()),
+            DhcpOption::DomainSearch(ref val) => val.byte_len(),
+            DhcpOption::ClasslessRoutes(ref vec) => vec.iter().fold(0, |v, r| v + r.get_size() as usize),
+            DhcpOption::Unknown(_, ref b) => (*b).len(),
         }
     }
 
@@ -349,13 +458,28 @@ impl DhcpOption {
             DhcpOption::AddressRequest(ref ip) => ip.push_to(buffer),
             DhcpOption::MessageType(ref t) => t.push_value(buffer),
             DhcpOption::ServerIdentifier(ref ip) => ip.push_to(buffer),
+            DhcpOption::ParameterRequestList(ref params) => buffer.extend(params.iter()),
             DhcpOption::Message(ref str) => buffer.extend(str.as_bytes().iter()),
+            DhcpOption::MaxMessageSize(s) =>
+                buffer.write_u16::<NetworkEndian>(s).unwrap(),
             DhcpOption::RenewalTime(t) =>
                 buffer.write_u32::<NetworkEndian>(t).unwrap(),
             DhcpOption::RebindingTime(t) =>
                 buffer.write_u32::<NetworkEndian>(t).unwrap(),
+            DhcpOption::VendorClassIdentifier(ref vc) =>
+                buffer.extend(vc.iter()),
             DhcpOption::ClientIdentifier(ref ci) =>
                 buffer.extend(ci.iter()),
+            DhcpOption::RelayAgentInformation(ref subs) => {
+                    // Sub-options can't be split like options, the ones that are too long are left out
+                    for sub in subs.iter() {
+                        if sub.fits() {
+                            sub.push_to(buffer);
+                        } else {
+                            warn!("Leaving out relay agent sub-option {} with {} bytes", sub.get_type(), sub.get_size());
+                        }
+                    }
+                },
             DhcpOption::DomainSearch(ref val) => val.serialize_onto(buffer),
             DhcpOption::ClasslessRoutes(ref routes) => {
                     for route in routes.iter() {
@@ -367,10 +491,27 @@ impl DhcpOption {
         }
     }
 
+    /// The content of the option as it's sent on the wire
+    pub fn get_value(&self) -> Vec<u8> {
+        let mut ret = Vec::with_capacity(self.get_size());
+        self.push_value(&mut ret);
+        ret
+    }
+
+    /// Push the option, split into multiple instances if it's longer than 255 bytes (RFC 3396)
     fn push_to(&self, buffer: &mut Vec<u8>) {
-        buffer.push(self.get_type());
-        buffer.push(self.get_size());
-        self.push_value(buffer);
+        let value = self.get_value();
+
+        if value.is_empty() {
+            buffer.push(self.get_type());
+            buffer.push(0);
+        }
+
+        for chunk in value.chunks(255) {
+            buffer.push(self.get_type());
+            buffer.push(chunk.len() as u8);
+            buffer.extend_from_slice(chunk);
+        }
     }
 
     fn ipv4s_from_buffer(buffer: &[u8]) -> Result<Box<[Ipv4Addr]>, String> {
@@ -408,6 +549,14 @@ impl DhcpOption {
         Ok(NetworkEndian::read_u32(buffer))
     }
 
+    fn u16_from_buffer(buffer: &[u8]) -> Result<u16, String> {
+        if buffer.len() != 2 {
+            return Err("Buffer for 16 bit DHCP option didn't have a size of 2".into());
+        }
+
+        Ok(NetworkEndian::read_u16(buffer))
+    }
+
     fn classless_routes_from_buffer(buffer: &[u8]) -> Result<Self, String> {
         let len = buffer.len();
         let mut i = 0;
@@ -425,6 +574,26 @@ impl DhcpOption {
         Ok(DhcpOption::ClasslessRoutes(ret.into_boxed_slice()))
     }
 
+    fn relay_info_from_buffer(buffer: &[u8]) -> Result<Self, String> {
+        let mut i = 0;
+        let mut ret = Vec::new();
+
+        while i < buffer.len() {
+            if buffer.len() < i + 2 {
+                return Err(String::from("Relay agent sub-option header would be longer than the buffer"));
+            }
+            let len = buffer[i + 1] as usize;
+            if buffer.len() < i + 2 + len {
+                return Err(String::from("Relay agent sub-option length is larger than buffer left to parse"));
+            }
+
+            ret.push(RelaySubOption::from_buffer(buffer[i], &buffer[i + 2..i + 2 + len])?);
+            i += len + 2;
+        }
+
+        Ok(DhcpOption::RelayAgentInformation(ret.into_boxed_slice()))
+    }
+
     fn bytes_from_buffer(buffer: &[u8]) -> Box<[u8]> {
         Vec::from(buffer).into_boxed_slice()
     }
@@ -442,10 +611,14 @@ impl DhcpOption {
             51 => Ok(DhcpOption::LeaseTime(Self::u32_from_buffer(buffer)?)),
             53 => Ok(DhcpOption::MessageType(PacketType::from_value(buffer[0])?)),
             54 => Ok(DhcpOption::ServerIdentifier(Self::ipv4_from_buffer(buffer)?)),
+            55 => Ok(DhcpOption::ParameterRequestList(Self::bytes_from_buffer(buffer))),
             56 => Ok(DhcpOption::Message(Self::string_from_buffer(buffer)?)),
+            57 => Ok(DhcpOption::MaxMessageSize(Self::u16_from_buffer(buffer)?)),
             58 => Ok(DhcpOption::RenewalTime(Self::u32_from_buffer(buffer)?)),
             59 => Ok(DhcpOption::RebindingTime(Self::u32_from_buffer(buffer)?)),
-            60 => Ok(DhcpOption::ClientIdentifier(Self::bytes_from_buffer(buffer))),
+            60 => Ok(DhcpOption::VendorClassIdentifier(Self::bytes_from_buffer(buffer))),
+            61 => Ok(DhcpOption::ClientIdentifier(Self::bytes_from_buffer(buffer))),
+            82 => Self::relay_info_from_buffer(buffer),
             119=> Ok(DhcpOption::DomainSearch(DomainNames::deserialize_from(buffer)?)),
             121=> Self::classless_routes_from_buffer(buffer),
             _  => Ok(DhcpOption::Unknown(variant, Self::bytes_from_buffer(buffer))),
@@ -460,6 +633,56 @@ impl DhcpOption {
     }
 }
 
+/// Size of the fixed part of a DHCP message, including the magic cookie
+const DHCP_HEADER_SIZE: usize = 240;
+/// The IP and UDP headers count against the maximum message size
+const IP_UDP_HEADER_SIZE: usize = 28;
+/// Every client has to accept messages of this size
+const MIN_MESSAGE_SIZE: u16 = 576;
+
+const SNAME_POS: usize = 44;
+const SNAME_SIZE: usize = 64;
+const FILE_POS: usize = 108;
+const FILE_SIZE: usize = 128;
+
+/// Options we send whether the client asked for them or not
+const MANDATORY_OPTIONS: [u8; 1] = [51];
+
+/// Select the options for a reply to a client that sent the parameter request list `requested`.
+/// Mandatory options come first, then the requested ones in the clients order and then the ones in
+/// `always`. Without a list from the client everything is sent.
+pub fn filter_options(opts: &[DhcpOption], requested: Option<&[u8]>, always: &[u8]) -> Vec<DhcpOption> {
+    let requested = match requested {
+            Some(x) => x,
+            None => return opts.to_vec(),
+        };
+
+    let mut ret: Vec<DhcpOption> = Vec::with_capacity(opts.len());
+    let codes = MANDATORY_OPTIONS.iter().chain(requested.iter()).chain(always.iter());
+    for code in codes {
+        if ret.iter().any(|opt| opt.get_type() == *code) {
+            continue;
+        }
+
+        if let Some(opt) = opts.iter().find(|opt| opt.get_type() == *code) {
+            ret.push(opt.clone());
+        }
+    }
+
+    ret
+}
+
+/// Merge the options of a more specific scope into `opts`.
+/// Options with the same code are replaced, new ones are appended.
+pub fn merge_options(opts: &mut Vec<DhcpOption>, overrides: &[DhcpOption]) {
+    for opt in overrides {
+        match opts.iter().position(|x| x.get_type() == opt.get_type()) {
+            Some(i) => opts[i] = opt.clone(),
+            None => opts.push(opt.clone()),
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum DhcpFlags {
     Broadcast
@@ -500,6 +723,9 @@ pub struct DhcpPacket<Hw> {
     pub client_hwaddr: Hw,
     pub options: Vec<DhcpOption>,
     pub flags: Vec<DhcpFlags>,
+    /// Largest IP datagram the receiver accepts, `None` for no limit.
+    /// Options that don't fit into the options field are moved into `file` and `sname`.
+    pub max_size: Option<u16>,
 }
 
 impl<Hw: PartialEq> PartialEq for DhcpPacket<Hw> {
@@ -545,10 +771,118 @@ impl<Hw: Arbitrary> Arbitrary for DhcpPacket<Hw> {
             client_hwaddr: Arbitrary::arbitrary(gen),
             options: opts,
             flags: flags,
+            max_size: None,
         }
     }
 }
 
+impl<Hw> DhcpPacket<Hw> {
+    /// The relay agent information (option 82) a relay attached to this packet
+    pub fn get_relay_info(&self) -> Option<&[RelaySubOption]> {
+        self.options.iter().filter_map(|opt|
+            match *opt {
+                DhcpOption::RelayAgentInformation(ref subs) => Some(&subs[..]),
+                _ => None
+            }).next()
+    }
+
+    /// The options the client asked for (option 55)
+    pub fn get_requested_options(&self) -> Option<&[u8]> {
+        self.options.iter().filter_map(|opt|
+            match *opt {
+                DhcpOption::ParameterRequestList(ref params) => Some(&params[..]),
+                _ => None
+            }).next()
+    }
+
+    /// The content of the option with `code` as it was sent
+    pub fn get_option_value(&self, code: u8) -> Option<Vec<u8>> {
+        self.options.iter().find(|opt| opt.get_type() == code).map(|opt| opt.get_value())
+    }
+
+    /// The user classes the client sent (option 77, RFC 3004).
+    /// Some clients send a single class without the length prefixes, that's returned as is.
+    pub fn get_user_classes(&self) -> Vec<Vec<u8>> {
+        let data = match self.get_option_value(77) {
+                Some(x) => x,
+                None => return Vec::new(),
+            };
+
+        let mut ret = Vec::new();
+        let mut i = 0;
+        while i < data.len() {
+            let len = data[i] as usize;
+            if len == 0 || data.len() < i + 1 + len {
+                return vec![data];
+            }
+            ret.push(data[i + 1..i + 1 + len].to_vec());
+            i += len + 1;
+        }
+
+        ret
+    }
+
+    /// The fully qualified domain name the client sent (option 81, RFC 4702) without the final dot
+    pub fn get_fqdn(&self) -> Option<String> {
+        let data = match self.get_option_value(81) {
+                Some(ref x) if x.len() > 3 => x.clone(),
+                _ => return None,
+            };
+
+        // The E flag tells us whether the name is in the DNS wire format or plain ASCII
+        let mut name = if data[0] & 0x04 != 0 {
+                let (name, _) = match name::Name::scan(3, &data) {
+                        Ok(x) => x,
+                        Err(e) => {
+                            debug!("Couldn't decode the client FQDN: {}", e);
+                            return None;
+                        }
+                    };
+                let labels: Vec<String> = name.map(|l| String::from_utf8_lossy(l).into_owned()).collect();
+                labels.join(".")
+            } else {
+                String::from_utf8_lossy(&data[3..]).into_owned()
+            };
+
+        if name.ends_with('.') {
+            name.pop();
+        }
+        Some(name)
+    }
+
+    /// The vendor class the client sent (option 60)
+    pub fn get_vendor_class(&self) -> Option<&[u8]> {
+        self.options.iter().filter_map(|opt|
+            match *opt {
+                DhcpOption::VendorClassIdentifier(ref class) => Some(&class[..]),
+                _ => None
+            }).next()
+    }
+
+    /// The largest message the client accepts (option 57), 576 if it didn't tell us
+    pub fn get_max_message_size(&self) -> u16 {
+        let size = self.options.iter().filter_map(|opt|
+            match *opt {
+                DhcpOption::MaxMessageSize(size) => Some(size),
+                _ => None
+            }).next();
+
+        cmp::max(size.unwrap_or(MIN_MESSAGE_SIZE), MIN_MESSAGE_SIZE)
+    }
+
+    /// The subnet a relayed request should be served from.
+    /// This is the link selection sub-option if the relay set it, the relays address otherwise.
+    pub fn get_link(&self) -> Option<Ipv4Addr> {
+        let selected = self.get_relay_info().and_then(|subs| subs.iter().filter_map(|sub|
+            match *sub {
+                RelaySubOption::LinkSelection(ip) => Some(ip),
+                _ => None
+            }).next());
+
+        selected.or(self.gateway_addr)
+    }
+}
+
 impl<Hw: HwAddr> DhcpPacket<Hw> {
     fn push_flags(&self, buffer: &mut Vec<u8>) {
         let value = self.flags.iter().fold(0, |acc, flag| acc |
@@ -564,6 +898,8 @@ flag.get_value());
     }
 
     pub fn serialize_with(&self, buffer: &mut Vec<u8>) {
+        let start = buffer.len();
+
         /* Static-ish foo */
         buffer.push(self.packet_type.get_op());
         buffer.push(Hw::hwtype());
@@ -590,12 +926,75 @@ flag.get_value());
         /* Magic cookie */
         buffer.write_u32::<NetworkEndian>(0x63_82_53_63).unwrap();
 
-        self.packet_type.push_to(buffer);
+        self.push_options(start, buffer);
+    }
+
+    /// Push the options, overloading `file` and `sname` if they don't fit into `max_size`.
+    /// The relay agent information always stays at the end of the options field, since relays
+    /// won't look for it anywhere else.
+    fn push_options(&self, start: usize, buffer: &mut Vec<u8>) {
+        let mut main = Vec::new();
+        self.packet_type.push_to(&mut main);
 
+        let mut relay = Vec::new();
+        let mut encoded = Vec::with_capacity(self.options.len());
         for option in &self.options {
-            option.push_to(buffer);
+            if option.get_type() == 82 {
+                option.push_to(&mut relay);
+            } else {
+                let mut opt = Vec::new();
+                option.push_to(&mut opt);
+                encoded.push(opt);
+            }
         }
 
+        /* End option included */
+        let needed = main.len() + relay.len() + encoded.iter().fold(1, |acc, opt| acc + opt.len());
+        let space = match self.max_size {
+                Some(size) => cmp::max(size, MIN_MESSAGE_SIZE) as usize - IP_UDP_HEADER_SIZE - DHCP_HEADER_SIZE,
+                None => needed,
+            };
+
+        if needed <= space {
+            for opt in encoded {
+                main.extend(opt);
+            }
+        } else {
+            /* Keep space for the overload and end option, file and sname need an end option */
+            let caps = [space.saturating_sub(relay.len() + 3 + 1), FILE_SIZE - 1, SNAME_SIZE - 1];
+            let mut fields = [main, Vec::new(), Vec::new()];
+            /* Clients read main, file and sname in that order, never go back to keep the order */
+            let mut current = 0;
+            for opt in encoded {
+                match (current..fields.len()).find(|&i| fields[i].len() + opt.len() <= caps[i]) {
+                    Some(i) => {
+                        current = i;
+                        fields[i].extend(opt);
+                    },
+                    None => warn!("Dropping option {} from reply to {}, it doesn't fit", opt[0], self.xid),
+                }
+            }
+
+            let mut overload = 0;
+            for &(field, pos, flag) in &[(1, FILE_POS, 1), (2, SNAME_POS, 2)] {
+                let data = &fields[field];
+                if data.is_empty() {
+                    continue;
+                }
+                let pos = start + pos;
+                buffer[pos..pos + data.len()].copy_from_slice(data);
+                buffer[pos + data.len()] = 0xff;
+                overload |= flag;
+            }
+
+            main = fields[0].clone();
+            if overload != 0 {
+                main.extend([52, 1, overload].iter());
+            }
+        }
+
+        buffer.extend(main);
+        buffer.extend(relay);
         buffer.push(0xff);
     }
 
@@ -615,8 +1014,8 @@ flag.get_value());
         }
     }
 
-    fn get_options(buffer: &[u8]) -> Result<Vec<DhcpOption>, String> {
-        let mut map = HashMap::new();
+    /// Collect the options in `buffer` into `map`, concatenating the ones that appear multiple times
+    fn scan_options(buffer: &[u8], map: &mut HashMap<u8, Vec<u8>>) -> Result<(), String> {
         let mut i = 0;
         loop {
             let opt = match buffer.get(i) {
@@ -649,6 +1048,24 @@ flag.get_value());
 
         }
 
+        Ok(())
+    }
+
+    fn get_options(buffer: &[u8]) -> Result<Vec<DhcpOption>, String> {
+        let mut map = HashMap::new();
+        Self::scan_options(&buffer[DHCP_HEADER_SIZE..], &mut map)?;
+
+        /* The overload option tells us to continue in the file and then in the sname field */
+        if let Some(overload) = map.remove(&52) {
+            let overload = overload.get(0).cloned().unwrap_or(0);
+            if overload & 1 != 0 {
+                Self::scan_options(&buffer[FILE_POS..FILE_POS + FILE_SIZE], &mut map)?;
+            }
+            if overload & 2 != 0 {
+                Self::scan_options(&buffer[SNAME_POS..SNAME_POS + SNAME_SIZE], &mut map)?;
+            }
+        }
+
         let mut ret = Vec::with_capacity(map.len());
         for (opt, buf) in map {
             let opt = DhcpOption::from_buffer(opt, buf.as_slice())?;
@@ -686,7 +1103,7 @@ flag.get_value());
         let gateway_addr = Self::get_ip(&buffer[24..]);
 
         let client_hwaddr = Hw::from_buffer(&buffer[28..]);
-        let options = Self::get_options(&buffer[cookie_pos + 4..])?;
+        let options = Self::get_options(buffer)?;
         let packet_type;
 
         {
@@ -709,6 +1126,7 @@ flag.get_value());
             client_hwaddr: client_hwaddr,
             flags: flags,
             options: options.into_iter().filter(|opt| !opt.is_message_type()).collect(),
+            max_size: None,
             })
     }
 }
@@ -731,7 +1149,132 @@ mod tests {
     use packet::PacketType;
     use packet::ClasslessRoute;
     use packet::DhcpOption;
+    use packet::RelaySubOption;
     use packet::DhcpPacket;
+    use packet::filter_options;
+    use packet::merge_options;
+    use std::collections::HashMap;
+    use std::iter;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn filters_options() {
+        let opts = vec![DhcpOption::SubnetMask(Ipv4Addr::new(255, 255, 255, 0)),
+                        DhcpOption::Router(vec![Ipv4Addr::new(10, 0, 0, 1)].into_boxed_slice()),
+                        DhcpOption::DomainName("example.com".into()),
+                        DhcpOption::LeaseTime(3600)];
+
+        assert!(filter_options(&opts, None, &[]) == opts);
+        assert!(filter_options(&opts, Some(&[3, 1, 42]), &[]) == vec![opts[3].clone(), opts[1].clone(), opts[0].clone()]);
+        assert!(filter_options(&opts, Some(&[1]), &[15, 1]) == vec![opts[3].clone(), opts[0].clone(), opts[2].clone()]);
+    }
+
+    #[test]
+    fn merges_options() {
+        let mut opts = vec![DhcpOption::Router(vec![Ipv4Addr::new(10, 0, 0, 1)].into_boxed_slice()),
+                            DhcpOption::LeaseTime(3600)];
+        merge_options(&mut opts, &[DhcpOption::DomainName("example.com".into()),
+                                   DhcpOption::Router(vec![Ipv4Addr::new(10, 0, 0, 2)].into_boxed_slice())]);
+
+        assert!(opts == vec![DhcpOption::Router(vec![Ipv4Addr::new(10, 0, 0, 2)].into_boxed_slice()),
+                             DhcpOption::LeaseTime(3600),
+                             DhcpOption::DomainName("example.com".into())]);
+    }
+
+    #[test]
+    fn leaves_out_long_sub_options() {
+        let opt = DhcpOption::RelayAgentInformation(vec![RelaySubOption::CircuitId(vec![1; 300].into_boxed_slice()),
+                                                         RelaySubOption::RemoteId(vec![2; 3].into_boxed_slice())].into_boxed_slice());
+        assert!(opt.get_value() == vec![2, 3, 2, 2, 2]);
+    }
+
+    #[test]
+    fn decodes_fqdn() {
+        let mut packet = DhcpPacket {
+                packet_type: PacketType::Request,
+                xid: 0,
+                seconds: 0,
+                client_addr: None,
+                your_addr: None,
+                server_addr: None,
+                gateway_addr: None,
+                client_hwaddr: EthernetAddr([0; 6]),
+                options: vec![DhcpOption::Unknown(81, b"\x05\0\0\x02pc\x03lab\0"[..].to_vec().into_boxed_slice())],
+                flags: Vec::new(),
+                max_size: None,
+            };
+        assert!(packet.get_fqdn() == Some("pc.lab".into()));
+
+        packet.options = vec![DhcpOption::Unknown(81, b"\x01\0\0pc.lab."[..].to_vec().into_boxed_slice())];
+        assert!(packet.get_fqdn() == Some("pc.lab".into()));
+    }
+
+    #[test]
+    fn splits_long_options() {
+        let opt = DhcpOption::Unknown(224, vec![7; 300].into_boxed_slice());
+        let mut buffer = Vec::new();
+        opt.push_to(&mut buffer);
+
+        assert!(buffer.len() == 304);
+        assert!(buffer[..2] == [224, 255] && buffer[257..259] == [224, 45]);
+    }
+
+    #[test]
+    fn overloads_options() {
+        let name = DhcpOption::DomainName(iter::repeat('a').take(250).collect());
+        let message = DhcpOption::Message(iter::repeat('b').take(100).collect());
+        let hostname = DhcpOption::Hostname(iter::repeat('c').take(50).collect());
+        let mut packet = DhcpPacket {
+            packet_type: PacketType::Ack,
+            xid: 42,
+            seconds: 0,
+            client_addr: None,
+            your_addr: Some(Ipv4Addr::new(10, 0, 0, 2)),
+            server_addr: None,
+            gateway_addr: None,
+            client_hwaddr: EthernetAddr([0, 1, 2, 3, 4, 5]),
+            options: vec![DhcpOption::LeaseTime(3600), name, message, hostname],
+            flags: Vec::new(),
+            max_size: None,
+        };
+
+        let unlimited = packet.serialize();
+        packet.max_size = Some(576);
+        let buffer = packet.serialize();
+
+        assert!(unlimited.len() > 576 - 28);
+        assert!(buffer.len() <= 576 - 28);
+        /* overload both file and sname */
+        assert!(buffer[108] == 56);
+        assert!(buffer[44] == 12);
+        assert!(DhcpPacket::deserialize(&buffer) == Ok(packet));
+    }
+
+    #[test]
+    fn keeps_order_when_overloading() {
+        let name = DhcpOption::DomainName(iter::repeat('a').take(250).collect());
+        let message = DhcpOption::Message(iter::repeat('b').take(100).collect());
+        let hostname = DhcpOption::Hostname(String::from("pc"));
+        let packet = DhcpPacket {
+            packet_type: PacketType::Ack,
+            xid: 42,
+            seconds: 0,
+            client_addr: None,
+            your_addr: Some(Ipv4Addr::new(10, 0, 0, 2)),
+            server_addr: None,
+            gateway_addr: None,
+            client_hwaddr: EthernetAddr([0, 1, 2, 3, 4, 5]),
+            options: vec![DhcpOption::LeaseTime(3600), name, message, hostname],
+            flags: Vec::new(),
+            max_size: Some(576),
+        };
+
+        let buffer = packet.serialize();
+        /* The hostname would fit into the main field, but it comes after the message in file */
+        assert!(buffer[108] == 56);
+        assert!(buffer[210..214] == [12, 2, b'p', b'c']);
+        assert!(!buffer[240..].windows(4).any(|w| w == [12, 2, b'p', b'c']));
+    }
 
     quickcheck! {
         fn serialize_type(packet: PacketType) -> bool {
@@ -751,7 +1294,11 @@ mod tests {
         fn serialize_dhcpoption(opt: DhcpOption) -> bool {
             let mut buffer = Vec::new();
             opt.push_to(&mut buffer);
-            let de = DhcpOption::from_buffer(opt.get_type(), &(buffer.as_slice()[2..]));
+            buffer.push(255);
+
+            let mut map = HashMap::new();
+            DhcpPacket::<EthernetAddr>::scan_options(&buffer, &mut map).unwrap();
+            let de = DhcpOption::from_buffer(opt.get_type(), &map[&opt.get_type()]);
             return Ok(opt) == de;
         }
 
@@ -761,5 +1308,13 @@ mod tests {
             println!("{:?}", de);
             return Ok(packet) == de;
         }
+
+        fn serialize_limited(packet: DhcpPacket<EthernetAddr>) -> bool {
+            let mut packet = packet;
+            packet.max_size = Some(576);
+            let buffer = packet.serialize();
+            let de = DhcpPacket::<EthernetAddr>::deserialize(buffer.as_slice()).unwrap();
+            return buffer.len() <= 576 - 28 && de.options.iter().all(|opt| packet.options.contains(opt));
+        }
     }
 }
@@ -22,11 +22,17 @@ pub struct DomainNames {
 }
 
 impl DomainNames {
-    pub fn byte_len(&self) -> u8 {
+    pub fn byte_len(&self) -> usize {
         self.names.iter().fold(0, |r, n| r + n.byte_len())
     }
 }
 
+impl From<Vec<DomainName>> for DomainNames {
+    fn from(names: Vec<DomainName>) -> Self {
+        DomainNames { names: names.into_boxed_slice() }
+    }
+}
+
 impl Serializeable for DomainNames {
     fn serialize_onto(&self, buffer: &mut Vec<u8>) {
         for name in self.names.iter() {
@@ -129,9 +135,9 @@ impl DomainName {
         DomainName { labels: v.into_boxed_slice() }
     }
 
-    fn byte_len(&self) -> u8 {
-        let format = self.labels.len() as u8 + 1;
-        self.labels.iter().fold(format, |i, l| i + l.len() as u8)
+    fn byte_len(&self) -> usize {
+        let format = self.labels.len() + 1;
+        self.labels.iter().fold(format, |i, l| i + l.len())
     }
 }
 
@@ -223,7 +229,8 @@ impl<'a> Iterator for Name<'a> {
 #[cfg(test)]
 impl Arbitrary for DomainName {
     fn arbitrary<G: Gen>(gen: &mut G) -> Self {
-        let g: Vec<Vec<u8>> = Arbitrary::arbitrary(gen);
+        let count = gen.gen_range(0, 8);
+        let g: Vec<Vec<u8>> = (0..count).map(|_| Arbitrary::arbitrary(gen)).collect();
         let r: Vec<Vec<u8>> = g.into_iter()
                 .map(|l| l
                      .into_iter()
@@ -143,7 +143,6 @@ impl<P: Poolable> GPool<P> {
         self.used.insert(ip.into_internal());
     }
 
-    #[cfg(test)]
     pub fn set_unused(&mut self, ip: &P) {
         self.used.remove(&ip.into_internal());
     }
@@ -153,6 +152,12 @@ impl<P: Poolable> GPool<P> {
         self.ranges.iter().any(|range| range.lower <= val && range.upper >= val)
     }
 
+    /// Whether any address from `lower` to `upper` is in the pool
+    pub fn overlaps(&self, lower: &P, upper: &P) -> bool {
+        let (lower, upper) = (lower.into_internal(), upper.into_internal());
+        self.ranges.iter().any(|range| range.lower <= upper && range.upper >= lower)
+    }
+
     pub fn is_used(&self, ip: &P) -> bool {
         self.used.contains(&ip.into_internal())
     }
@@ -0,0 +1,200 @@
+extern crate serde;
+extern crate serde_json;
+
+use std;
+use std::io::{Error, ErrorKind, Result};
+use std::net::Ipv4Addr;
+
+use self::serde_json::{Map, Value};
+
+use frame::ethernet::EthernetAddr;
+use lease;
+use store::State;
+
+/// The version of the layout we write.
+/// Bump it when the layout changes and add a migration from the old one to `decode_state`.
+///
+/// Version 0 was a bare list in a file per part of the state, version 1 wrapped them in `{"version", "entries"}`.
+/// Since version 2 the whole state is in one file with the generation of the snapshot.
+pub const VERSION: u64 = 2;
+
+/// Written by a newer server, we don't know how to read it without losing something
+#[derive(Debug)]
+pub struct NewerVersion {
+    pub found: u64,
+}
+
+impl std::fmt::Display for NewerVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "The saved state has format version {}, this server only knows up to version {}. Refusing to use it so no leases get lost",
+               self.found, VERSION)
+    }
+}
+
+impl std::error::Error for NewerVersion {
+    fn description(&self) -> &str { "newer lease format" }
+}
+
+/// Whether `e` is about state in a format we don't know yet
+pub fn is_newer(e: &Error) -> bool {
+    e.get_ref().map(|inner| inner.is::<NewerVersion>()).unwrap_or(false)
+}
+
+/// Fail if `version` is newer than the one we know
+pub fn check_version(version: u64) -> Result<()> {
+    if version > VERSION {
+        return Err(Error::new(ErrorKind::InvalidData, NewerVersion { found: version }));
+    }
+
+    Ok(())
+}
+
+/// The whole state of a pool in one file, so it's always replaced as a whole
+#[derive(Serialize)]
+struct Snapshot<'a> {
+    version: u64,
+    /// Counts the snapshots, the journal records the one it belongs to
+    generation: u64,
+    allocations: &'a [lease::Allocation<EthernetAddr, Ipv4Addr>],
+    leases: &'a [lease::Lease<EthernetAddr, Ipv4Addr>],
+    quarantined: &'a [lease::Quarantine<EthernetAddr, Ipv4Addr>],
+}
+
+/// The file content for `state` in the current format
+pub fn encode_state(state: &State, generation: u64) -> Result<String> {
+    Ok(serde_json::to_string(&Snapshot {
+            version: VERSION,
+            generation: generation,
+            allocations: &state.allocations,
+            leases: &state.leases,
+            quarantined: &state.quarantined,
+        })?)
+}
+
+/// Read a file written by `encode_state`, with its generation.
+/// Version 1 files may have no generation, their journal has none either.
+pub fn decode_state(content: &str) -> Result<(State, Option<u64>)> {
+    let mut map = match serde_json::from_str(content)? {
+            Value::Object(map) => map,
+            _ => return Err(Error::new(ErrorKind::InvalidData, "The saved state isn't an object")),
+        };
+    let version = get_version(&map)?;
+    check_version(version)?;
+    let generation = map.get("generation").and_then(|v| v.as_u64());
+    if generation.is_none() && version > 1 {
+        return Err(Error::new(ErrorKind::InvalidData, "The saved state has no generation"));
+    }
+
+    Ok((State {
+            allocations: from_entries(take_entries(&mut map, "allocations")?)?,
+            leases: from_entries(take_entries(&mut map, "leases")?)?,
+            quarantined: from_entries(take_entries(&mut map, "quarantined")?)?,
+        }, generation))
+}
+
+/// Read a file with a single list, `{"version": 1, "entries": [...]}` or the bare list of version 0.
+/// Older versions are migrated.
+pub fn decode<T: serde::de::DeserializeOwned>(content: &str) -> Result<Vec<T>> {
+    let value: Value = serde_json::from_str(content)?;
+    let entries = match value {
+            // Version 0 was the bare list
+            Value::Array(entries) => migrate_v0(entries),
+            Value::Object(mut map) => {
+                check_version(get_version(&map)?)?;
+                take_entries(&mut map, "entries")?
+            },
+            _ => return Err(Error::new(ErrorKind::InvalidData, "The saved state is neither a list nor versioned")),
+        };
+
+    from_entries(entries)
+}
+
+fn get_version(map: &Map<String, Value>) -> Result<u64> {
+    map.get("version").and_then(|v| v.as_u64())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "The saved state has no version"))
+}
+
+fn take_entries(map: &mut Map<String, Value>, key: &str) -> Result<Vec<Value>> {
+    match map.remove(key) {
+        Some(Value::Array(entries)) => Ok(entries),
+        _ => Err(Error::new(ErrorKind::InvalidData, format!("The saved state has no {}", key))),
+    }
+}
+
+fn from_entries<T: serde::de::DeserializeOwned>(entries: Vec<Value>) -> Result<Vec<T>> {
+    let mut ret = Vec::with_capacity(entries.len());
+    for entry in entries {
+        ret.push(serde_json::from_value(entry)?);
+    }
+    Ok(ret)
+}
+
+/// Version 0 allocations may lack `forever`, they were written before reservations existed.
+/// Clients may have the vendor class in `client_identifier`, it was stored there by mistake.
+fn migrate_v0(mut entries: Vec<Value>) -> Vec<Value> {
+    let mut dropped = 0;
+    for entry in &mut entries {
+        if let Value::Object(ref mut map) = *entry {
+            if map.contains_key("last_seen") && !map.contains_key("forever") {
+                map.insert(String::from("forever"), Value::Bool(false));
+            }
+            if let Some(&mut Value::Object(ref mut client)) = map.get_mut("client") {
+                if client.remove("client_identifier").map(|x| !x.is_null()).unwrap_or(false) {
+                    dropped += 1;
+                }
+            }
+        }
+    }
+
+    if dropped > 0 {
+        warn!("Dropped {} stored client identifiers, older versions kept the vendor class there", dropped);
+    }
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, decode_state, encode_state, is_newer};
+    use frame::ethernet::EthernetAddr;
+    use lease;
+    use std::net::Ipv4Addr;
+    use store::State;
+
+    type Allocation = lease::Allocation<EthernetAddr, Ipv4Addr>;
+
+    #[test]
+    fn migrates_v0() {
+        let old = r#"[{"assigned":"0.0.0.2","client":{"hw_addr":[0,0,0,0,0,1],"client_identifier":[77,83,70,84],"hostname":null},"last_seen":[5,0]}]"#;
+        let allocs: Vec<Allocation> = decode(old).unwrap();
+
+        assert!(allocs.len() == 1 && !allocs[0].forever);
+        assert!(allocs[0].client.client_identifier.is_none());
+        assert!(decode::<Allocation>(r#"{"version":1,"entries":[]}"#).unwrap().is_empty());
+    }
+
+    #[test]
+    fn refuses_newer() {
+        let err = decode::<Allocation>(r#"{"version":1000,"entries":[]}"#).unwrap_err();
+        assert!(is_newer(&err));
+        assert!(!is_newer(&decode::<Allocation>("{").unwrap_err()));
+        assert!(is_newer(&decode_state(r#"{"version":1000}"#).unwrap_err()));
+    }
+
+    #[test]
+    fn round_trips_state() {
+        let old = r#"[{"assigned":"0.0.0.2","client":{"hw_addr":[0,0,0,0,0,1],"client_identifier":null,"hostname":null},"last_seen":[5,0],"forever":true}]"#;
+        let state = State { allocations: decode(old).unwrap(), leases: Vec::new(), quarantined: Vec::new() };
+
+        let (read, generation) = decode_state(&encode_state(&state, 7).unwrap()).unwrap();
+        assert!(read.allocations == state.allocations && read.leases.is_empty() && read.quarantined.is_empty());
+        assert!(generation == Some(7));
+    }
+
+    #[test]
+    fn migrates_v1_state() {
+        let (state, generation) = decode_state(r#"{"version":1,"allocations":[],"leases":[],"quarantined":[]}"#).unwrap();
+        assert!(state.allocations.is_empty() && generation.is_none());
+        assert!(decode_state(r#"{"version":1,"generation":3,"allocations":[],"leases":[],"quarantined":[]}"#).unwrap().1 == Some(3));
+        assert!(decode_state(r#"{"version":2,"allocations":[],"leases":[],"quarantined":[]}"#).is_err());
+    }
+}
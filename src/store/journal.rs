@@ -0,0 +1,132 @@
+extern crate serde_json;
+
+use std;
+use std::io::{BufRead, BufReader, Result, Write};
+use std::net::Ipv4Addr;
+
+use frame::ethernet::EthernetAddr;
+use lease;
+use store::format;
+use store::State;
+
+type Allocation = lease::Allocation<EthernetAddr, Ipv4Addr>;
+type Lease = lease::Lease<EthernetAddr, Ipv4Addr>;
+type Quarantine = lease::Quarantine<EthernetAddr, Ipv4Addr>;
+
+/// Write the snapshot again after this many entries
+pub const COMPACT_AFTER: usize = 1024;
+
+/// A change to the state of an allocator, entries are keyed by the address
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Entry {
+    Allocate(Allocation),
+    Deallocate(Ipv4Addr),
+    Lease(Lease),
+    Release(Ipv4Addr),
+    Quarantine(Quarantine),
+    Unquarantine(Ipv4Addr),
+}
+
+/// Apply one entry to the state
+pub fn apply(entry: Entry, state: &mut State) {
+    match entry {
+        Entry::Allocate(alloc) => {
+            state.allocations.retain(|a| a.assigned != alloc.assigned);
+            state.allocations.push(alloc);
+        },
+        Entry::Deallocate(addr) => state.allocations.retain(|a| a.assigned != addr),
+        Entry::Lease(lease) => {
+            state.leases.retain(|l| l.assigned != lease.assigned);
+            state.leases.push(lease);
+        },
+        Entry::Release(addr) => state.leases.retain(|l| l.assigned != addr),
+        Entry::Quarantine(q) => {
+            state.quarantined.retain(|x| x.assigned != q.assigned);
+            state.quarantined.push(q);
+        },
+        Entry::Unquarantine(addr) => state.quarantined.retain(|q| q.assigned != addr),
+    }
+}
+
+/// The first line of a journal, journals without one are from version 0 and have the same entries
+#[derive(Serialize, Deserialize)]
+struct Header {
+    version: u64,
+    /// The generation of the snapshot the entries apply to.
+    /// Journals written along the separate files of older versions have none.
+    #[serde(default)]
+    generation: Option<u64>,
+}
+
+/// Append `entries` to the journal at `path`, one JSON object per line.
+/// A new journal starts with a header for the snapshot of `generation`.
+pub fn append(path: &std::path::Path, generation: u64, entries: &[Entry]) -> Result<()> {
+    let mut file = std::fs::OpenOptions::new().append(true).create(true).open(path)?;
+
+    let mut data = Vec::new();
+    if file.metadata()?.len() == 0 {
+        serde_json::to_writer(&mut data, &Header { version: format::VERSION, generation: Some(generation) })?;
+        data.push(b'\n');
+    }
+    for entry in entries {
+        serde_json::to_writer(&mut data, entry)?;
+        data.push(b'\n');
+    }
+
+    file.write_all(&data)?;
+    file.sync_data()
+}
+
+/// Read the journal at `path` and the generation of the snapshot it belongs to.
+/// A damaged last line is what we get when we crash while appending, it's skipped.
+pub fn read(path: &std::path::Path) -> Result<(Option<u64>, Vec<Entry>)> {
+    let file = std::fs::File::open(path)?;
+    let lines = BufReader::new(file).lines().collect::<Result<Vec<String>>>()?;
+
+    let mut generation = None;
+    let mut ret = Vec::with_capacity(lines.len());
+    for (i, line) in lines.iter().enumerate() {
+        if i == 0 {
+            if let Ok(header) = serde_json::from_str::<Header>(line) {
+                format::check_version(header.version)?;
+                generation = header.generation;
+                continue;
+            }
+        }
+
+        match serde_json::from_str(line) {
+            Ok(entry) => ret.push(entry),
+            Err(ref e) if i + 1 == lines.len() => warn!("Skipping the incomplete last entry of {}: {}", path.to_string_lossy(), e),
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok((generation, ret))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply, Entry};
+    use frame::ethernet::EthernetAddr;
+    use lease;
+    use std::net::Ipv4Addr;
+    use store::State;
+    use time;
+
+    #[test]
+    fn replays_entries() {
+        let alloc = |last: u8, sec: i64| lease::Allocation {
+                assigned: Ipv4Addr::new(10, 0, 0, last),
+                client: lease::Client{client_identifier: None, hostname: None, hw_addr: EthernetAddr([0, 0, 0, 0, 0, last])},
+                last_seen: lease::SerializeableTime(time::Timespec::new(sec, 0)),
+                forever: false,
+            };
+        let mut state = State { allocations: vec![alloc(1, 0), alloc(2, 0)], leases: Vec::new(), quarantined: Vec::new() };
+        let entries = vec![Entry::Deallocate(Ipv4Addr::new(10, 0, 0, 1)), Entry::Allocate(alloc(2, 5)), Entry::Allocate(alloc(3, 0)), Entry::Release(Ipv4Addr::new(10, 0, 0, 2))];
+
+        for entry in entries {
+            apply(entry, &mut state);
+        }
+        assert!(state.allocations == vec![alloc(2, 5), alloc(3, 0)]);
+    }
+}
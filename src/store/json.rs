@@ -0,0 +1,235 @@
+extern crate serde;
+
+use std;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::path::{Path, PathBuf};
+
+use store::{format, journal, LeaseStore, State};
+
+/// The state of a pool, written as a whole
+const STATE: &str = "state.json";
+
+/// Older versions kept the state in one file for each part
+const LEGACY: [&str; 3] = ["allocations.json", "leases.json", "declined.json"];
+
+/// A JSON file in a directory per pool, with a journal of the changes since it was written
+pub struct JsonStore {
+    dir: PathBuf,
+    /// The generation of the last snapshot we read or wrote for each pool
+    generations: HashMap<String, u64>,
+}
+
+impl JsonStore {
+    pub fn new(dir: PathBuf) -> Self {
+        JsonStore { dir: dir, generations: HashMap::new() }
+    }
+
+    fn pool_dir(&self, pool: &str) -> Result<PathBuf> {
+        let my_dir = self.dir.join(pool);
+        if !(my_dir.exists() && my_dir.is_dir()){
+            return Err(Error::new(ErrorKind::NotFound, format!("Couldn't find directory {} while trying to read allocator from file", my_dir.to_string_lossy())));
+        }
+
+        Ok(my_dir)
+    }
+
+    /// The content of `path`, `None` if there's no such file
+    fn read_content(path: &Path) -> Result<Option<String>> {
+        let mut file = match std::fs::File::open(path) {
+                Ok(x) => x,
+                Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e),
+            };
+        let mut content = String::new();
+        file.read_to_string(&mut content)?;
+        Ok(Some(content))
+    }
+
+    /// Tell which file `e` is about.
+    /// A newer format stays as it is, the caller has to see it mustn't fall back to the backup.
+    fn with_path(path: &Path, e: Error) -> Error {
+        if format::is_newer(&e) {
+            e
+        } else {
+            Error::new(e.kind(), format!("{}: {}", path.to_string_lossy(), e))
+        }
+    }
+
+    fn read_file<T>(path: &Path) -> Result<Vec<T>>
+        where T: serde::de::DeserializeOwned {
+        match Self::read_content(path)? {
+            Some(content) => format::decode(&content).map_err(|e| Self::with_path(path, e)),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Read the state file with `suffix` and its generation, or the older separate files if there's none.
+    /// The separate files and state files of version 1 may have no generation.
+    fn read_snapshot(dir: &Path, suffix: &str) -> Result<(State, Option<u64>)> {
+        let path = dir.join(format!("{}{}", STATE, suffix));
+        match Self::read_content(&path)? {
+            Some(content) => format::decode_state(&content).map_err(|e| Self::with_path(&path, e)),
+            None => Ok((State {
+                    allocations: Self::read_file(&dir.join(format!("{}{}", LEGACY[0], suffix)))?,
+                    leases: Self::read_file(&dir.join(format!("{}{}", LEGACY[1], suffix)))?,
+                    quarantined: Self::read_file(&dir.join(format!("{}{}", LEGACY[2], suffix)))?,
+                }, None)),
+        }
+    }
+
+    /// The generation for the next snapshot of `pool`, larger than any that's on disk
+    fn next_generation(&self, dir: &Path, pool: &str) -> u64 {
+        if let Some(generation) = self.generations.get(pool) {
+            return generation + 1;
+        }
+
+        // We couldn't read the snapshot, the journal may still be there
+        let snapshot = Self::read_snapshot(dir, "").ok().and_then(|(_, generation)| generation);
+        let journal = journal::read(&dir.join("journal.json")).ok().and_then(|(generation, _)| generation);
+        std::cmp::max(snapshot, journal).map(|generation| generation + 1).unwrap_or(1)
+    }
+
+    /// Replace `path` with `data` without ever leaving a partially written file behind.
+    /// The content it had before is kept as backup in `<path>.bak`.
+    fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+        let name = path.file_name().map(|x| x.to_string_lossy().into_owned()).unwrap_or_default();
+        let tmp = path.with_file_name(format!("{}.tmp", name));
+        {
+            let mut file = std::fs::File::create(&tmp)?;
+            file.write_all(data)?;
+            file.sync_all()?;
+        }
+
+        if path.exists() {
+            let backup = path.with_file_name(format!("{}.bak", name));
+            let _ = std::fs::remove_file(&backup);
+            std::fs::hard_link(path, &backup).or_else(|_| std::fs::copy(path, &backup).map(|_| ()))?;
+        }
+
+        std::fs::rename(&tmp, path)?;
+        // The rename is only durable once the directory is synced
+        if let Some(dir) = path.parent() {
+            std::fs::File::open(dir)?.sync_all()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl LeaseStore for JsonStore {
+    fn read(&mut self, pool: &str) -> Result<State> {
+        let my_dir = self.pool_dir(pool)?;
+        let (mut state, generation) = Self::read_snapshot(&my_dir, "")?;
+
+        // We crashed after writing a snapshot but before removing the journal, it's already in there.
+        // It's removed once the next snapshot is written, without a generation nothing gets appended to it before.
+        let mut stale = false;
+        match journal::read(&my_dir.join("journal.json")) {
+            Ok((written, entries)) => if written == generation {
+                info!("Replaying {} journal entries for {}", entries.len(), pool);
+                for entry in entries {
+                    journal::apply(entry, &mut state);
+                }
+            } else {
+                warn!("Skipping the journal of {}, it belongs to an older snapshot", pool);
+                stale = true;
+            },
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {},
+            Err(e) => return Err(e),
+        }
+
+        if stale {
+            self.generations.remove(pool);
+        } else if let Some(generation) = generation {
+            self.generations.insert(pool.to_string(), generation);
+        }
+        Ok(state)
+    }
+
+    /// The journal belongs to the snapshot that failed to read, it isn't replayed
+    fn read_backup(&mut self, pool: &str) -> Result<State> {
+        let my_dir = self.pool_dir(pool)?;
+        let has_backup = |name: &str| my_dir.join(format!("{}.bak", name)).exists();
+        if !has_backup(STATE) && !LEGACY.iter().any(|f| has_backup(f)) {
+            return Err(Error::new(ErrorKind::NotFound, format!("There's no backup in {}", my_dir.to_string_lossy())));
+        }
+
+        Self::read_snapshot(&my_dir, ".bak").map(|(state, _)| state)
+    }
+
+    fn write(&mut self, pool: &str, state: &State) -> Result<()> {
+        let my_dir = self.dir.join(pool);
+        std::fs::create_dir_all(my_dir.as_path())?;
+
+        // Taken even if the write fails, the file may still have made it to the disk
+        let generation = self.next_generation(&my_dir, pool);
+        self.generations.insert(pool.to_string(), generation);
+        Self::write_atomic(&my_dir.join(STATE), format::encode_state(state, generation)?.as_bytes()).map_err(|e| {
+                error!("Failed to write the state of {} to file: {}", pool, e);
+                e
+            })?;
+
+        // The state file has everything that's in the older files and the journal
+        let mut stale = vec![String::from("journal.json")];
+        for name in LEGACY.iter() {
+            stale.push(name.to_string());
+            stale.push(format!("{}.bak", name));
+        }
+        for name in stale {
+            match std::fs::remove_file(my_dir.join(name)) {
+                Err(ref e) if e.kind() == ErrorKind::NotFound => {},
+                x => x?,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn append(&mut self, pool: &str, entries: &[journal::Entry]) -> Result<()> {
+        let my_dir = self.dir.join(pool);
+        let generation = match self.generations.get(pool) {
+                Some(&x) => x,
+                None => return Err(Error::new(ErrorKind::NotFound, format!("There's no snapshot of {} the journal could belong to", pool))),
+            };
+        journal::append(&my_dir.join("journal.json"), generation, entries)
+    }
+
+    fn pools(&mut self) -> Result<Vec<String>> {
+        let entries = match std::fs::read_dir(&self.dir) {
+                Ok(x) => x,
+                Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+                Err(e) => return Err(e),
+            };
+
+        let mut ret = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                ret.push(entry.file_name().to_string_lossy().into_owned());
+            }
+        }
+        Ok(ret)
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> Result<()> {
+        let target = self.dir.join(to);
+        if target.exists() {
+            return Err(Error::new(ErrorKind::AlreadyExists, format!("{} already exists", target.to_string_lossy())));
+        }
+
+        std::fs::rename(self.dir.join(from), &target)?;
+        if let Some(generation) = self.generations.remove(from) {
+            self.generations.insert(to.to_string(), generation);
+        }
+        std::fs::File::open(&self.dir)?.sync_all()
+    }
+
+    fn remove(&mut self, pool: &str) -> Result<()> {
+        self.generations.remove(pool);
+        match std::fs::remove_dir_all(self.dir.join(pool)) {
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            x => x,
+        }
+    }
+}
@@ -0,0 +1,83 @@
+pub mod format;
+pub mod journal;
+pub mod json;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+use std;
+use std::io::{Error, ErrorKind, Result};
+use std::net::Ipv4Addr;
+use std::os::unix::io::AsRawFd;
+
+use libc;
+
+use config;
+use frame::ethernet::EthernetAddr;
+use lease;
+
+/// Everything an allocator has to remember
+#[derive(Debug, Default)]
+pub struct State {
+    pub allocations: Vec<lease::Allocation<EthernetAddr, Ipv4Addr>>,
+    pub leases: Vec<lease::Lease<EthernetAddr, Ipv4Addr>>,
+    pub quarantined: Vec<lease::Quarantine<EthernetAddr, Ipv4Addr>>,
+}
+
+/// Where the allocators of an interface keep their state, `pool` is the name of the allocator
+pub trait LeaseStore: Send {
+    /// The state of `pool`, empty if there's none yet
+    fn read(&mut self, pool: &str) -> Result<State>;
+
+    /// An older state of `pool`, for when `read` failed
+    fn read_backup(&mut self, pool: &str) -> Result<State>;
+
+    /// Replace the whole state of `pool`
+    fn write(&mut self, pool: &str, state: &State) -> Result<()>;
+
+    /// Record the changes since the last write
+    fn append(&mut self, pool: &str, entries: &[journal::Entry]) -> Result<()>;
+
+    /// The names of all pools with saved state
+    fn pools(&mut self) -> Result<Vec<String>>;
+
+    /// Move the state of `from` to `to`, there mustn't be any for `to` yet
+    fn rename(&mut self, from: &str, to: &str) -> Result<()>;
+
+    /// Forget the state of `pool`, it was removed from the config
+    fn remove(&mut self, pool: &str) -> Result<()>;
+}
+
+/// Make sure no other process uses the state in `cache_dir`.
+/// The lock is held until the returned file is dropped.
+pub fn lock(cache_dir: &str) -> Result<std::fs::File> {
+    std::fs::create_dir_all(cache_dir)?;
+    let path = std::path::Path::new(cache_dir).join("dhcpd.lock");
+    let file = std::fs::OpenOptions::new().create(true).write(true).truncate(false).open(&path)?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        let e = Error::last_os_error();
+        if e.kind() == ErrorKind::WouldBlock {
+            return Err(Error::new(ErrorKind::WouldBlock, format!("{} is locked, is the server running?", path.display())));
+        }
+        return Err(e);
+    }
+
+    Ok(file)
+}
+
+/// Open the store for `iface` configured in `storage`
+pub fn open(storage: &config::Storage, cache_dir: &str, iface: &str) -> Result<Box<LeaseStore>> {
+    match *storage {
+        config::Storage::Json => Ok(Box::new(json::JsonStore::new(std::path::Path::new(cache_dir).join(iface)))),
+        config::Storage::Sqlite(ref path) => open_sqlite(path, iface),
+    }
+}
+
+#[cfg(feature = "sqlite")]
+fn open_sqlite(path: &str, iface: &str) -> Result<Box<LeaseStore>> {
+    Ok(Box::new(sqlite::SqliteStore::open(path, iface)?))
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn open_sqlite(_: &str, _: &str) -> Result<Box<LeaseStore>> {
+    Err(Error::new(ErrorKind::Other, "This version was compiled without support for sqlite"))
+}
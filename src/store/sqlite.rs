@@ -0,0 +1,271 @@
+extern crate rusqlite;
+extern crate time;
+
+use self::rusqlite::Connection;
+use self::rusqlite::types::ToSql;
+
+use std::io::{Error, ErrorKind, Result};
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+
+use frame::ethernet::EthernetAddr;
+use lease;
+use store::{format, journal, LeaseStore, State};
+
+/// A SQLite database shared by all interfaces, one row per allocation, lease and quarantined address.
+/// Other tools can read the tables while the server is running.
+pub struct SqliteStore {
+    conn: Connection,
+    iface: String,
+}
+
+const SCHEMA: &'static str = "
+    CREATE TABLE IF NOT EXISTS allocations (
+        interface TEXT NOT NULL, pool TEXT NOT NULL, address TEXT NOT NULL,
+        hw_addr TEXT NOT NULL, client_id BLOB, hostname TEXT,
+        last_seen INTEGER NOT NULL, forever INTEGER NOT NULL,
+        PRIMARY KEY (interface, pool, address));
+    CREATE TABLE IF NOT EXISTS leases (
+        interface TEXT NOT NULL, pool TEXT NOT NULL, address TEXT NOT NULL,
+        hw_addr TEXT NOT NULL, client_id BLOB, hostname TEXT,
+        start INTEGER NOT NULL, duration INTEGER NOT NULL,
+        PRIMARY KEY (interface, pool, address));
+    CREATE TABLE IF NOT EXISTS declined (
+        interface TEXT NOT NULL, pool TEXT NOT NULL, address TEXT NOT NULL,
+        hw_addr TEXT NOT NULL, client_id BLOB, hostname TEXT,
+        start INTEGER NOT NULL, duration INTEGER NOT NULL,
+        PRIMARY KEY (interface, pool, address));";
+
+fn to_io(e: rusqlite::Error) -> Error {
+    Error::new(ErrorKind::Other, e)
+}
+
+fn parse<T: FromStr>(value: &str) -> Result<T> {
+    T::from_str(value).map_err(|_| Error::new(ErrorKind::InvalidData, format!("Couldn't parse {} from the database", value)))
+}
+
+fn get_time(secs: i64) -> lease::SerializeableTime {
+    lease::SerializeableTime(time::Timespec::new(secs, 0))
+}
+
+/// The columns every table shares, after interface and pool
+struct Row {
+    address: String,
+    hw_addr: String,
+    client_id: Option<Vec<u8>>,
+    hostname: Option<String>,
+    time: i64,
+    value: i64,
+}
+
+impl Row {
+    fn get_client(&self) -> Result<lease::Client<EthernetAddr>> {
+        Ok(lease::Client {
+            hw_addr: parse(&self.hw_addr)?,
+            client_identifier: self.client_id.clone().map(|x| x.into_boxed_slice()),
+            hostname: self.hostname.clone(),
+        })
+    }
+}
+
+impl SqliteStore {
+    /// Open the database at `path`, the schema version is kept in `user_version`
+    pub fn open(path: &str, iface: &str) -> Result<Self> {
+        Self::with_connection(Connection::open(path).map_err(to_io)?, iface)
+    }
+
+    fn with_connection(conn: Connection, iface: &str) -> Result<Self> {
+        let version: i64 = conn.query_row("PRAGMA user_version", &[], |row| row.get(0)).map_err(to_io)?;
+        format::check_version(version as u64)?;
+
+        // A new database and one from before the version was recorded both have version 0, the schema hasn't changed since version 1
+        conn.execute_batch(SCHEMA).map_err(to_io)?;
+        conn.execute_batch(&format!("PRAGMA user_version = {}", format::VERSION)).map_err(to_io)?;
+
+        Ok(SqliteStore { conn: conn, iface: iface.to_string() })
+    }
+
+    fn read_table(&self, table: &str, pool: &str) -> Result<Vec<Row>> {
+        let time = if table == "allocations" { "last_seen" } else { "start" };
+        let value = if table == "allocations" { "forever" } else { "duration" };
+        let mut stmt = self.conn.prepare(&format!(
+                "SELECT address, hw_addr, client_id, hostname, {}, {} FROM {} WHERE interface = ?1 AND pool = ?2",
+                time, value, table)).map_err(to_io)?;
+        let rows = stmt.query_map(&[&self.iface, &pool], |row| Row {
+                address: row.get(0),
+                hw_addr: row.get(1),
+                client_id: row.get(2),
+                hostname: row.get(3),
+                time: row.get(4),
+                value: row.get(5),
+            }).map_err(to_io)?;
+
+        let mut ret = Vec::new();
+        for row in rows {
+            ret.push(row.map_err(to_io)?);
+        }
+        Ok(ret)
+    }
+
+    fn insert(conn: &Connection, iface: &str, pool: &str, table: &str,
+              addr: Ipv4Addr, client: &lease::Client<EthernetAddr>, time: i64, value: i64) -> Result<()> {
+        let client_id = client.client_identifier.as_ref().map(|x| x.to_vec());
+        conn.execute(&format!("INSERT OR REPLACE INTO {} VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)", table),
+                     &[&iface, &pool, &addr.to_string(), &client.hw_addr.to_string(),
+                       &client_id as &ToSql, &client.hostname, &time, &value]).map_err(to_io)?;
+        Ok(())
+    }
+
+    fn delete(conn: &Connection, iface: &str, pool: &str, table: &str, addr: Ipv4Addr) -> Result<()> {
+        conn.execute(&format!("DELETE FROM {} WHERE interface = ?1 AND pool = ?2 AND address = ?3", table),
+                     &[&iface, &pool, &addr.to_string()]).map_err(to_io)?;
+        Ok(())
+    }
+
+    fn apply(conn: &Connection, iface: &str, pool: &str, entry: &journal::Entry) -> Result<()> {
+        match *entry {
+            journal::Entry::Allocate(ref a) =>
+                Self::insert(conn, iface, pool, "allocations", a.assigned, &a.client, a.last_seen.sec, a.forever as i64),
+            journal::Entry::Deallocate(addr) => Self::delete(conn, iface, pool, "allocations", addr),
+            journal::Entry::Lease(ref l) =>
+                Self::insert(conn, iface, pool, "leases", l.assigned, &l.client, l.lease_start.sec, i64::from(l.lease_duration)),
+            journal::Entry::Release(addr) => Self::delete(conn, iface, pool, "leases", addr),
+            journal::Entry::Quarantine(ref q) =>
+                Self::insert(conn, iface, pool, "declined", q.assigned, &q.client, q.start.sec, i64::from(q.duration)),
+            journal::Entry::Unquarantine(addr) => Self::delete(conn, iface, pool, "declined", addr),
+        }
+    }
+}
+
+impl LeaseStore for SqliteStore {
+    fn read(&mut self, pool: &str) -> Result<State> {
+        let mut state = State::default();
+        for row in self.read_table("allocations", pool)? {
+            state.allocations.push(lease::Allocation {
+                assigned: parse(&row.address)?,
+                client: row.get_client()?,
+                last_seen: get_time(row.time),
+                forever: row.value != 0,
+            });
+        }
+        for row in self.read_table("leases", pool)? {
+            state.leases.push(lease::Lease {
+                assigned: parse(&row.address)?,
+                client: row.get_client()?,
+                lease_start: get_time(row.time),
+                lease_duration: row.value as u32,
+            });
+        }
+        for row in self.read_table("declined", pool)? {
+            state.quarantined.push(lease::Quarantine {
+                assigned: parse(&row.address)?,
+                client: row.get_client()?,
+                start: get_time(row.time),
+                duration: row.value as u32,
+            });
+        }
+
+        Ok(state)
+    }
+
+    /// SQLite doesn't leave broken data behind on a crash, there's nothing to fall back to
+    fn read_backup(&mut self, pool: &str) -> Result<State> {
+        Err(Error::new(ErrorKind::NotFound, format!("There's no backup for {} in the database", pool)))
+    }
+
+    fn write(&mut self, pool: &str, state: &State) -> Result<()> {
+        let tx = self.conn.transaction().map_err(to_io)?;
+        for table in &["allocations", "leases", "declined"] {
+            tx.execute(&format!("DELETE FROM {} WHERE interface = ?1 AND pool = ?2", table), &[&self.iface, &pool]).map_err(to_io)?;
+        }
+        for a in &state.allocations {
+            Self::insert(&tx, &self.iface, pool, "allocations", a.assigned, &a.client, a.last_seen.sec, a.forever as i64)?;
+        }
+        for l in &state.leases {
+            Self::insert(&tx, &self.iface, pool, "leases", l.assigned, &l.client, l.lease_start.sec, i64::from(l.lease_duration))?;
+        }
+        for q in &state.quarantined {
+            Self::insert(&tx, &self.iface, pool, "declined", q.assigned, &q.client, q.start.sec, i64::from(q.duration))?;
+        }
+        tx.commit().map_err(to_io)
+    }
+
+    fn append(&mut self, pool: &str, entries: &[journal::Entry]) -> Result<()> {
+        let tx = self.conn.transaction().map_err(to_io)?;
+        for entry in entries {
+            Self::apply(&tx, &self.iface, pool, entry)?;
+        }
+        tx.commit().map_err(to_io)
+    }
+
+    fn pools(&mut self) -> Result<Vec<String>> {
+        let mut stmt = self.conn.prepare(
+                "SELECT pool FROM allocations WHERE interface = ?1
+                 UNION SELECT pool FROM leases WHERE interface = ?1
+                 UNION SELECT pool FROM declined WHERE interface = ?1").map_err(to_io)?;
+        let rows = stmt.query_map(&[&self.iface], |row| row.get(0)).map_err(to_io)?;
+
+        let mut ret = Vec::new();
+        for row in rows {
+            ret.push(row.map_err(to_io)?);
+        }
+        Ok(ret)
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> Result<()> {
+        if self.pools()?.iter().any(|p| p == to) {
+            return Err(Error::new(ErrorKind::AlreadyExists, format!("There's already state for {} in the database", to)));
+        }
+
+        let tx = self.conn.transaction().map_err(to_io)?;
+        for table in &["allocations", "leases", "declined"] {
+            tx.execute(&format!("UPDATE {} SET pool = ?3 WHERE interface = ?1 AND pool = ?2", table), &[&self.iface, &from, &to]).map_err(to_io)?;
+        }
+        tx.commit().map_err(to_io)
+    }
+
+    fn remove(&mut self, pool: &str) -> Result<()> {
+        let tx = self.conn.transaction().map_err(to_io)?;
+        for table in &["allocations", "leases", "declined"] {
+            tx.execute(&format!("DELETE FROM {} WHERE interface = ?1 AND pool = ?2", table), &[&self.iface, &pool]).map_err(to_io)?;
+        }
+        tx.commit().map_err(to_io)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::rusqlite::Connection;
+    use super::SqliteStore;
+    use frame::ethernet::EthernetAddr;
+    use lease;
+    use std::net::Ipv4Addr;
+    use store::{journal, LeaseStore, State};
+    use time;
+
+    #[test]
+    fn round_trips() {
+        let mut store = SqliteStore::with_connection(Connection::open_in_memory().unwrap(), "eth0").unwrap();
+        let client = lease::Client{client_identifier: Some(vec![1, 0, 0, 0, 0, 0, 1].into_boxed_slice()), hostname: Some("laptop".into()), hw_addr: EthernetAddr([0, 0, 0, 0, 0, 1])};
+        // The database keeps whole seconds
+        let now = lease::SerializeableTime(time::Timespec::new(time::get_time().sec, 0));
+        let alloc = lease::Allocation { assigned: Ipv4Addr::new(10, 0, 0, 5), client: client.clone(), last_seen: now, forever: true };
+        let lease = lease::Lease { assigned: Ipv4Addr::new(10, 0, 0, 5), client: client.clone(), lease_start: now, lease_duration: 3600 };
+        let declined = lease::Quarantine { assigned: Ipv4Addr::new(10, 0, 0, 6), client: client.clone(), start: now, duration: 600 };
+
+        store.write("lan", &State { allocations: vec![alloc.clone()], leases: vec![lease], quarantined: Vec::new() }).unwrap();
+        store.append("lan", &[journal::Entry::Release(Ipv4Addr::new(10, 0, 0, 5)), journal::Entry::Quarantine(declined.clone())]).unwrap();
+
+        let state = store.read("lan").unwrap();
+        assert!(state.allocations == vec![alloc] && state.leases.is_empty() && state.quarantined == vec![declined]);
+        assert!(store.pools().unwrap() == vec![String::from("lan")]);
+
+        store.rename("lan", "office").unwrap();
+        assert!(store.pools().unwrap() == vec![String::from("office")]);
+        assert!(store.read("office").unwrap().allocations.len() == 1);
+        assert!(store.read("lan").unwrap().allocations.is_empty());
+
+        store.remove("office").unwrap();
+        assert!(store.pools().unwrap().is_empty());
+    }
+}